@@ -0,0 +1,428 @@
+use crate::core::resolve_function;
+use crate::mods::load_order;
+use crate::serialization::json_value_to_lua_value;
+use crate::structs::commandentry::{
+    ArgKind, CommandArg, CommandCompletion, CommandConflict, CommandEntry,
+};
+use crate::structs::localmod::ModCommand;
+use mlua::prelude::{LuaError, LuaResult, LuaTable};
+use mlua::{Function, Lua, MultiValue, Value};
+use serde_json::Value as JsonValue;
+
+// Parses the arguments of a usage string such as `give <card> [count:integer]`. Words outside
+// of brackets, like the command name, only describe the command.
+pub fn parse_usage(usage: &str) -> Result<Vec<CommandArg>, String> {
+    let mut args: Vec<CommandArg> = Vec::new();
+    for word in usage.split_whitespace() {
+        // `<words>...` and `<words...>` are both accepted
+        let (word, rest) = match word.strip_suffix("...") {
+            Some(word) => (word, true),
+            None => (word, false),
+        };
+        let (inner, optional) = if let Some(inner) = word.strip_prefix('<') {
+            (inner.strip_suffix('>'), false)
+        } else if let Some(inner) = word.strip_prefix('[') {
+            (inner.strip_suffix(']'), true)
+        } else {
+            continue;
+        };
+        let inner = inner.ok_or(format!("Unclosed argument in usage: {}", word))?;
+        let (inner, rest) = match inner.strip_suffix("...") {
+            Some(inner) => (inner, true),
+            None => (inner, rest),
+        };
+        let (name, kind) = match inner.split_once(':') {
+            Some((name, kind)) => (name, ArgKind::parse(kind)?),
+            None => (inner, ArgKind::String),
+        };
+        if name.is_empty() {
+            return Err(format!("Unnamed argument in usage: {}", word));
+        }
+        if rest && kind != ArgKind::String {
+            return Err(format!(
+                "Argument {} takes the rest of the line, it must be a string",
+                name
+            ));
+        }
+        if let Some(last) = args.last() {
+            if last.rest {
+                return Err(format!("Argument {} comes after {}", name, last.describe()));
+            }
+            if last.optional && !optional {
+                return Err(format!(
+                    "Required argument {} comes after an optional one",
+                    name
+                ));
+            }
+        }
+        args.push(CommandArg {
+            name: name.to_string(),
+            kind,
+            optional,
+            rest,
+        });
+    }
+    Ok(args)
+}
+
+// Splits a console line into words, quotes group words and a backslash escapes a character
+pub fn split_command_line(line: &str) -> Result<Vec<String>, String> {
+    Ok(command_line_words(line)?
+        .into_iter()
+        .map(|(_, word)| word)
+        .collect())
+}
+
+// The words of a console line with the byte offset of their first character in the line,
+// which is not the word's length from the end once quotes or escapes are removed
+pub fn command_line_words(line: &str) -> Result<Vec<(usize, String)>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<(usize, String)> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                let (_, escaped) = chars
+                    .next()
+                    .ok_or("Nothing to escape at the end of the line")?;
+                word.get_or_insert((i, String::new())).1.push(escaped);
+            }
+            '"' | '\'' if quote.is_none() => {
+                quote = Some(c);
+                word.get_or_insert((i, String::new()));
+            }
+            _ if quote == Some(c) => quote = None,
+            c if c.is_whitespace() && quote.is_none() => {
+                if let Some(word) = word.take() {
+                    words.push(word);
+                }
+            }
+            c => word.get_or_insert((i, String::new())).1.push(c),
+        }
+    }
+    if let Some(quote) = quote {
+        return Err(format!("Unterminated {} quote", quote));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+// Words separated by whitespace only, for completing a line with an unterminated quote
+fn whitespace_words(line: &str) -> Vec<(usize, String)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        if !c.is_whitespace() {
+            start.get_or_insert(i);
+        } else if let Some(start) = start.take() {
+            words.push((start, line[start..i].to_string()));
+        }
+    }
+    if let Some(start) = start {
+        words.push((start, line[start..].to_string()));
+    }
+    words
+}
+
+// Converts the words following a command name to the types of its arguments, missing optional
+// arguments are null
+pub fn parse_args(args: &[CommandArg], words: &[String]) -> Result<Vec<JsonValue>, String> {
+    let mut values = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        if arg.rest {
+            if words.len() <= i {
+                if !arg.optional {
+                    return Err(format!("Missing argument {}", arg.describe()));
+                }
+                values.push(JsonValue::Null);
+            } else {
+                values.push(JsonValue::String(words[i..].join(" ")));
+            }
+            return Ok(values);
+        }
+        let word = match words.get(i) {
+            Some(word) => word,
+            None if arg.optional => {
+                values.push(JsonValue::Null);
+                continue;
+            }
+            None => return Err(format!("Missing argument {}", arg.describe())),
+        };
+        let invalid = || {
+            format!(
+                "Invalid {} for argument {}: {}",
+                arg.kind.as_str(),
+                arg.name,
+                word
+            )
+        };
+        let value = match arg.kind {
+            ArgKind::String => JsonValue::String(word.clone()),
+            ArgKind::Integer => JsonValue::from(word.parse::<i64>().map_err(|_| invalid())?),
+            ArgKind::Number => word
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64)
+                .map(JsonValue::Number)
+                .ok_or_else(invalid)?,
+            ArgKind::Boolean => match word.to_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => JsonValue::Bool(true),
+                "false" | "no" | "off" | "0" => JsonValue::Bool(false),
+                _ => return Err(invalid()),
+            },
+        };
+        values.push(value);
+    }
+    if words.len() > args.len() {
+        return Err(format!(
+            "Too many arguments, expected at most {}",
+            args.len()
+        ));
+    }
+    Ok(values)
+}
+
+// Commands of the enabled mods, kept in the Lua state
+#[derive(Debug, Default)]
+pub struct CommandRegistry {
+    // in mod load order
+    commands: Vec<CommandEntry>,
+}
+
+impl CommandRegistry {
+    // Adds a command of `mod_id`. A name already taken by a mod loaded earlier is a conflict,
+    // the command can then only be run by its qualified name `mod_id:name`.
+    pub fn add(
+        &mut self,
+        mod_id: &str,
+        command: &ModCommand,
+    ) -> Result<Option<CommandConflict>, String> {
+        let args = parse_usage(&command.usage)?;
+        if self.get(&format!("{}:{}", mod_id, command.name)).is_some() {
+            return Err(format!("Command {} is declared twice", command.name));
+        }
+        let conflict = self
+            .commands
+            .iter()
+            .find(|entry| entry.name == command.name)
+            .map(|entry| CommandConflict {
+                name: command.name.clone(),
+                mod_id: entry.mod_id.clone(),
+                other_mod_id: mod_id.to_string(),
+            });
+        self.commands.push(CommandEntry {
+            name: command.name.clone(),
+            mod_id: mod_id.to_string(),
+            lua_path: command.lua_path.clone(),
+            short_description: command.short_description.clone(),
+            usage: command.usage.clone(),
+            args,
+        });
+        Ok(conflict)
+    }
+
+    // Looks up `name`, or `mod_id:name` for the command of a given mod
+    pub fn get(&self, name: &str) -> Option<&CommandEntry> {
+        match name.split_once(':') {
+            Some((mod_id, name)) => self
+                .commands
+                .iter()
+                .find(|entry| entry.mod_id == mod_id && entry.name == name),
+            None => self.commands.iter().find(|entry| entry.name == name),
+        }
+    }
+
+    pub fn entries(&self) -> Vec<CommandEntry> {
+        self.commands.clone()
+    }
+
+    // The names a command can be run by, qualified when several mods declare it
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .commands
+            .iter()
+            .map(|entry| {
+                let shared = self
+                    .commands
+                    .iter()
+                    .filter(|other| other.name == entry.name)
+                    .count()
+                    > 1;
+                if shared {
+                    format!("{}:{}", entry.mod_id, entry.name)
+                } else {
+                    entry.name.clone()
+                }
+            })
+            .collect();
+        // the plain name still runs the first mod's command
+        names.extend(self.commands.iter().map(|entry| entry.name.clone()));
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    pub fn complete(&self, line: &str) -> CommandCompletion {
+        let (starts, words): (Vec<usize>, Vec<String>) = command_line_words(line)
+            .unwrap_or_else(|_| whitespace_words(line))
+            .into_iter()
+            .unzip();
+        let typing_new_word = words.is_empty() || line.ends_with(char::is_whitespace);
+        if words.len() <= 1 && !typing_new_word {
+            let candidates: Vec<String> = self
+                .names()
+                .into_iter()
+                .filter(|name| name.starts_with(words[0].as_str()))
+                .collect();
+            let hint = self.get(&words[0]).map(|entry| describe_args(&entry.args));
+            return CommandCompletion { candidates, hint };
+        }
+        if words.is_empty() {
+            return CommandCompletion {
+                candidates: self.names(),
+                hint: None,
+            };
+        }
+
+        let entry = match self.get(&words[0]) {
+            Some(entry) => entry,
+            None => {
+                return CommandCompletion {
+                    candidates: Vec::new(),
+                    hint: None,
+                }
+            }
+        };
+        // index of the argument being typed
+        let index = if typing_new_word {
+            words.len() - 1
+        } else {
+            words.len() - 2
+        };
+        let remaining = match entry.args.iter().position(|arg| arg.rest) {
+            Some(rest) if rest < index => &entry.args[rest..],
+            _ => entry.args.get(index..).unwrap_or_default(),
+        };
+        let mut candidates = Vec::new();
+        if remaining
+            .first()
+            .is_some_and(|arg| arg.kind == ArgKind::Boolean)
+        {
+            let (typed, line_start) = if typing_new_word {
+                ("", line)
+            } else {
+                let start = *starts.last().unwrap();
+                (words.last().unwrap().as_str(), &line[..start])
+            };
+            for value in ["true", "false"] {
+                if value.starts_with(typed) {
+                    candidates.push(format!("{}{}", line_start, value));
+                }
+            }
+        }
+        CommandCompletion {
+            candidates,
+            hint: Some(describe_args(remaining)).filter(|hint| !hint.is_empty()),
+        }
+    }
+}
+
+fn describe_args(args: &[CommandArg]) -> String {
+    args.iter()
+        .map(|arg| arg.describe())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn with_commands<T>(lua: &Lua, f: impl FnOnce(&mut CommandRegistry) -> T) -> T {
+    if lua.app_data_ref::<CommandRegistry>().is_none() {
+        lua.set_app_data(CommandRegistry::default());
+    }
+    let mut commands = lua.app_data_mut::<CommandRegistry>().unwrap();
+    f(&mut commands)
+}
+
+// Collects the commands of the enabled mods in `mods` (as returned by sort_mods), replacing the
+// ones registered before. Returns the names declared by several mods.
+pub fn register_commands(lua: &Lua, mods: LuaTable) -> LuaResult<Vec<CommandConflict>> {
+    let mut registry = CommandRegistry::default();
+    let mut conflicts = Vec::new();
+    for mod_id in load_order(mods.clone())? {
+        let mod_table: LuaTable = mods.get(mod_id.as_str())?;
+        let commands = mod_table
+            .get::<_, Option<Vec<ModCommand>>>("commands")?
+            .unwrap_or_default();
+        for command in commands {
+            match registry.add(&mod_id, &command) {
+                Ok(Some(conflict)) => {
+                    println!(
+                        "Command {} of {} is already declared by {}, run it as {}:{}",
+                        conflict.name,
+                        conflict.other_mod_id,
+                        conflict.mod_id,
+                        conflict.other_mod_id,
+                        conflict.name
+                    );
+                    conflicts.push(conflict);
+                }
+                Ok(None) => {}
+                Err(e) => println!(
+                    "Command {} of {} not registered: {}",
+                    command.name, mod_id, e
+                ),
+            }
+        }
+    }
+    lua.set_app_data(registry);
+    Ok(conflicts)
+}
+
+// The function a command runs: a global such as `my_mod.commands.give`, or the `give` field of
+// the module `my_mod.commands`
+fn command_function<'lua>(lua: &'lua Lua, lua_path: &str) -> LuaResult<Function<'lua>> {
+    if let Some(function) = resolve_function(lua, lua_path) {
+        return Ok(function);
+    }
+    if let Some((module, field)) = lua_path.rsplit_once('.') {
+        let require: Function = lua.globals().get("require")?;
+        if let Ok(Value::Table(module)) = require.call::<_, Value>(module) {
+            if let Value::Function(function) = module.get(field)? {
+                return Ok(function);
+            }
+        }
+    }
+    Err(LuaError::RuntimeError(format!(
+        "{} is not a function",
+        lua_path
+    )))
+}
+
+// Runs a console line such as `give j_joker 2`, returning what the command returns
+pub fn run_command<'lua>(lua: &'lua Lua, line: String) -> LuaResult<MultiValue<'lua>> {
+    let words = split_command_line(&line).map_err(LuaError::RuntimeError)?;
+    let name = match words.first() {
+        Some(name) => name,
+        None => return Ok(MultiValue::new()),
+    };
+    let entry = with_commands(lua, |commands| commands.get(name).cloned())
+        .ok_or(LuaError::RuntimeError(format!("Unknown command: {}", name)))?;
+    let values = parse_args(&entry.args, &words[1..])
+        .map_err(|e| LuaError::RuntimeError(format!("{}\nUsage: {}", e, entry.usage)))?;
+    let function = command_function(lua, &entry.lua_path).map_err(|e| {
+        LuaError::RuntimeError(format!("Command {} of {}: {}", entry.name, entry.mod_id, e))
+    })?;
+    let args = values
+        .into_iter()
+        .map(|value| json_value_to_lua_value(lua, value))
+        .collect::<LuaResult<Vec<Value>>>()?;
+    function.call(MultiValue::from_vec(args))
+}
+
+pub fn list_commands(lua: &Lua) -> Vec<CommandEntry> {
+    with_commands(lua, |commands| commands.entries())
+}
+
+pub fn complete_command(lua: &Lua, line: String) -> CommandCompletion {
+    with_commands(lua, |commands| commands.complete(&line))
+}
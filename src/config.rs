@@ -0,0 +1,215 @@
+use crate::structs::configfield::ConfigField;
+use crate::utils::parse_version;
+use serde::Deserialize;
+use serde_json::{Map, Value as JsonValue};
+use std::collections::HashMap;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Migration {
+    pub version: String,
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+    #[serde(default)]
+    pub set: Map<String, JsonValue>,
+}
+
+// Builds the default value described by a schema, recursing into object properties
+pub fn schema_defaults(schema: &JsonValue) -> Option<JsonValue> {
+    let mut default = schema.get("default").cloned();
+    if let Some(properties) = schema.get("properties").and_then(|p| p.as_object()) {
+        let mut object = match default.take() {
+            Some(JsonValue::Object(object)) => object,
+            _ => Map::new(),
+        };
+        for (key, property) in properties {
+            if let Some(property_default) = schema_defaults(property) {
+                match object.get_mut(key) {
+                    Some(existing) => merge_defaults(existing, &property_default),
+                    None => {
+                        object.insert(key.clone(), property_default);
+                    }
+                }
+            }
+        }
+        if !object.is_empty() {
+            default = Some(JsonValue::Object(object));
+        }
+    }
+    default
+}
+
+// Fills every key missing from `data` with its value from `defaults`, keeping existing values
+pub fn merge_defaults(data: &mut JsonValue, defaults: &JsonValue) {
+    match (data, defaults) {
+        (data @ JsonValue::Null, defaults) => *data = defaults.clone(),
+        (JsonValue::Object(data), JsonValue::Object(defaults)) => {
+            for (key, default) in defaults {
+                match data.get_mut(key) {
+                    Some(value) => merge_defaults(value, default),
+                    None => {
+                        data.insert(key.clone(), default.clone());
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+pub fn get_path<'a>(data: &'a JsonValue, path: &str) -> Option<&'a JsonValue> {
+    path.split('.').try_fold(data, |value, key| value.get(key))
+}
+
+pub fn set_path(data: &mut JsonValue, path: &str, value: JsonValue) {
+    let mut current = data;
+    let keys: Vec<&str> = path.split('.').collect();
+    for key in &keys[..keys.len() - 1] {
+        if !current.is_object() {
+            *current = JsonValue::Object(Map::new());
+        }
+        current = current
+            .as_object_mut()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert_with(|| JsonValue::Object(Map::new()));
+    }
+    if !current.is_object() {
+        *current = JsonValue::Object(Map::new());
+    }
+    current
+        .as_object_mut()
+        .unwrap()
+        .insert(keys[keys.len() - 1].to_string(), value);
+}
+
+pub fn remove_path(data: &mut JsonValue, path: &str) -> Option<JsonValue> {
+    let (parent, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (path_mut(data, parent)?, key),
+        None => (data, path),
+    };
+    parent.as_object_mut()?.remove(key)
+}
+
+fn path_mut<'a>(data: &'a mut JsonValue, path: &str) -> Option<&'a mut JsonValue> {
+    path.split('.')
+        .try_fold(data, |value, key| value.get_mut(key))
+}
+
+// The version a config saved before config.version existed is migrated from
+pub const UNVERSIONED: &str = "0.0.0";
+
+// Applies every migration newer than `from` and up to `to`, in version order.
+// Returns the versions of the migrations that were applied.
+pub fn migrate(
+    data: &mut JsonValue,
+    migrations: &[Migration],
+    from: &str,
+    to: &str,
+) -> Vec<String> {
+    let (from, to) = match (parse_version(from), parse_version(to)) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Vec::new(),
+    };
+    let mut pending: Vec<(semver::Version, &Migration)> = migrations
+        .iter()
+        .filter_map(|migration| parse_version(&migration.version).map(|v| (v, migration)))
+        .filter(|(version, _)| *version > from && *version <= to)
+        .collect();
+    pending.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut applied = Vec::new();
+    for (_, migration) in pending {
+        for (old_path, new_path) in migration.rename.iter() {
+            if let Some(value) = remove_path(data, old_path) {
+                set_path(data, new_path, value);
+            }
+        }
+        for path in migration.remove.iter() {
+            remove_path(data, path);
+        }
+        for (path, value) in migration.set.iter() {
+            set_path(data, path, value.clone());
+        }
+        applied.push(migration.version.clone());
+    }
+    applied
+}
+
+// Describes every property of an object schema so the Lua side can render a settings screen
+pub fn config_fields(schema: &JsonValue, config: &JsonValue) -> Vec<ConfigField> {
+    fn describe(key: &str, path: String, schema: &JsonValue, config: &JsonValue) -> ConfigField {
+        let number = |name: &str| schema.get(name).and_then(|n| n.as_f64());
+        // `exclusiveMinimum` is a bound of its own, or in draft 4 a flag on `minimum`
+        let bound = |name: &str, exclusive: &str| match schema.get(exclusive) {
+            Some(JsonValue::Bool(flag)) => (number(name), *flag && number(name).is_some()),
+            Some(JsonValue::Number(bound)) => (bound.as_f64(), true),
+            _ => (number(name), false),
+        };
+        let (minimum, exclusive_minimum) = bound("minimum", "exclusiveMinimum");
+        let (maximum, exclusive_maximum) = bound("maximum", "exclusiveMaximum");
+        let field_type = if schema.get("enum").is_some() {
+            "enum".to_string()
+        } else {
+            match schema.get("type") {
+                Some(JsonValue::String(field_type)) => field_type.clone(),
+                Some(JsonValue::Array(types)) => types
+                    .iter()
+                    .filter_map(|t| t.as_str())
+                    .find(|t| *t != "null")
+                    .unwrap_or("null")
+                    .to_string(),
+                _ if schema.get("properties").is_some() => "object".to_string(),
+                _ => "unknown".to_string(),
+            }
+        };
+        let label = match schema.get("title").and_then(|t| t.as_str()) {
+            Some(title) => title.to_string(),
+            None => {
+                let label = key.replace('_', " ");
+                let mut chars = label.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => label,
+                }
+            }
+        };
+        let value = get_path(config, &path).cloned();
+        ConfigField {
+            key: key.to_string(),
+            field_type,
+            label,
+            description: schema
+                .get("description")
+                .and_then(|d| d.as_str())
+                .map(|d| d.to_string()),
+            minimum,
+            maximum,
+            exclusive_minimum,
+            exclusive_maximum,
+            step: number("multipleOf"),
+            options: schema.get("enum").and_then(|e| e.as_array()).cloned(),
+            default: schema_defaults(schema),
+            value,
+            fields: match schema.get("properties").and_then(|p| p.as_object()) {
+                Some(properties) => properties
+                    .iter()
+                    .map(|(child, property)| {
+                        describe(child, format!("{}.{}", path, child), property, config)
+                    })
+                    .collect(),
+                None => Vec::new(),
+            },
+            path,
+        }
+    }
+
+    match schema.get("properties").and_then(|p| p.as_object()) {
+        Some(properties) => properties
+            .iter()
+            .map(|(key, property)| describe(key, key.clone(), property, config))
+            .collect(),
+        None => Vec::new(),
+    }
+}
@@ -1,8 +1,13 @@
+use crate::lua::lexer::{tokenize, TokenKind};
+use crate::lua::patch::{apply_edits, edited_regions, excerpt, AstTarget, TextTarget};
+use crate::patches::with_registry;
+use crate::sources::load_source_index;
 use crate::structs::modinfo::ModInfo;
-use crate::utils::{extract_functions, get_lua_files, minify_lua};
+use crate::structs::patchrecord::PatchKind;
+use crate::utils::minify_lua;
 use mlua::prelude::LuaResult;
-use mlua::{Lua, Table, Value};
-use serde_json::Value as JsonValue;
+use mlua::{Function, Lua, Table, Value};
+use std::collections::{HashMap, HashSet};
 #[cfg(not(target_os = "android"))]
 use std::env;
 use std::path::Path;
@@ -14,87 +19,6 @@ pub fn need_update(lua: &Lua, _: ()) -> LuaResult<bool> {
     super::updater::need_update(current_version)
 }
 
-fn lua_value_to_json_value(value: &Value) -> JsonValue {
-    match value {
-        Value::Nil => JsonValue::Null,
-        Value::Boolean(b) => JsonValue::Bool(*b),
-        Value::Integer(i) => JsonValue::Number((*i).into()),
-        Value::Number(n) => JsonValue::Number(serde_json::Number::from_f64(*n).unwrap()),
-        Value::String(s) => JsonValue::String(s.to_str().unwrap().to_string()),
-        Value::Table(table) => table_to_json_value(table),
-        _ => JsonValue::Null,
-    }
-}
-
-fn table_to_json_value(table: &Table) -> JsonValue {
-    let mut map = serde_json::Map::new();
-    let table_clone = table.clone();
-    for pair in table_clone.pairs::<Value, Value>() {
-        if let Ok((key, value)) = pair {
-            if let Value::String(k) = key {
-                map.insert(
-                    k.to_str().unwrap().to_string(),
-                    lua_value_to_json_value(&value),
-                );
-            } else if let Value::Integer(k) = key {
-                map.insert(k.to_string(), lua_value_to_json_value(&value));
-            } else if let Value::Number(k) = key {
-                map.insert(k.to_string(), lua_value_to_json_value(&value));
-            }
-        }
-    }
-    JsonValue::Object(map)
-}
-
-pub fn lua_to_json(table: Value) -> LuaResult<String> {
-    let json_value = lua_value_to_json_value(&table);
-    let json = serde_json::to_string(&json_value);
-    match json {
-        Ok(json) => Ok(json),
-        Err(e) => Err(mlua::Error::RuntimeError(format!("Error: {}", e))),
-    }
-}
-
-pub fn json_to_lua(lua: &Lua, json: String) -> LuaResult<Value> {
-    let value: serde_json::Value = serde_json::from_str(&json)
-        .map_err(|e| mlua::Error::RuntimeError(format!("Error parsing JSON: {}", e)))?;
-
-    json_value_to_lua_value(lua, value)
-}
-
-fn json_value_to_lua_value(lua: &Lua, value: serde_json::Value) -> LuaResult<Value> {
-    match value {
-        serde_json::Value::Null => Ok(Value::Nil),
-        serde_json::Value::Bool(b) => Ok(Value::Boolean(b)),
-        serde_json::Value::Number(num) => {
-            if let Some(n) = num.as_i64() {
-                Ok(Value::Integer(n))
-            } else if let Some(n) = num.as_f64() {
-                Ok(Value::Number(n))
-            } else {
-                Err(mlua::Error::RuntimeError("Invalid number".to_string()))
-            }
-        }
-        serde_json::Value::String(s) => Ok(Value::String(lua.create_string(&s)?)),
-        serde_json::Value::Array(arr) => {
-            let tbl = lua.create_table()?;
-            for (i, elem) in arr.into_iter().enumerate() {
-                let lua_value = json_value_to_lua_value(lua, elem)?;
-                tbl.set(i + 1, lua_value)?;
-            }
-            Ok(Value::Table(tbl))
-        }
-        serde_json::Value::Object(obj) => {
-            let tbl = lua.create_table()?;
-            for (key, value) in obj.into_iter() {
-                let lua_value = json_value_to_lua_value(lua, value)?;
-                tbl.set(key, lua_value)?;
-            }
-            Ok(Value::Table(tbl))
-        }
-    }
-}
-
 pub fn get_love_dir(lua: &Lua) -> LuaResult<String> {
     lua.load("love.filesystem.getSaveDirectory()")
         .eval::<String>()
@@ -147,8 +71,6 @@ pub fn restart() -> LuaResult<()> {
 }
 
 pub fn setup_injection(lua: &Lua) -> LuaResult<()> {
-    let files = get_lua_files();
-
     if lua
         .load("if game_state then return true else return false end")
         .eval::<bool>()?
@@ -159,15 +81,12 @@ pub fn setup_injection(lua: &Lua) -> LuaResult<()> {
 
     // creating the table, it's tables in a table like file_name/function_name/function_code
     let table = lua.create_table()?;
-    for (name, content) in files {
-        let functions = extract_functions(content);
+    for (name, functions) in load_source_index(lua)? {
         let file_table = lua.create_table()?;
         for (fn_name, code) in functions {
-            let mut code = code;
-            if code.contains("\n") {
-                code = minify_lua(code);
-            }
-            file_table.set(fn_name.clone(), code)?;
+            // before any mod wraps it
+            original_function(lua, &name, &fn_name)?;
+            file_table.set(fn_name, code)?;
         }
         table.set(name, file_table)?;
     }
@@ -183,29 +102,308 @@ pub fn inject(
     function: String,
     code_to_find: String,
     code_to_insert: String,
-) -> LuaResult<()> {
+    target: TextTarget,
+    mod_id: Option<String>,
+) -> LuaResult<usize> {
+    // regex patterns and their substitutions are matched against the minified code as given
+    let (code_to_find, code_to_insert) = if target.regex {
+        (code_to_find, code_to_insert)
+    } else {
+        (minify_lua(code_to_find), minify_lua(code_to_insert))
+    };
+
+    let kind = PatchKind::Text {
+        find: code_to_find,
+        target,
+    };
+    let mod_id = mod_id.unwrap_or_else(|| "unknown".to_string());
+    apply_patch(lua, &mod_id, &file, &function, kind, &code_to_insert, None)
+}
+
+pub fn inject_at(
+    lua: &Lua,
+    file: String,
+    function: String,
+    target: AstTarget,
+    code_to_insert: String,
+    mod_id: Option<String>,
+) -> LuaResult<usize> {
     let code_to_insert = minify_lua(code_to_insert);
-    let code_to_find = minify_lua(code_to_find);
+    let mod_id = mod_id.unwrap_or_else(|| "unknown".to_string());
+    let kind = PatchKind::Ast(target);
+    apply_patch(lua, &mod_id, &file, &function, kind, &code_to_insert, None)
+}
+
+// Patches the function's current code, records the patch and reports conflicts with other
+// mods. `order` is set when replaying a patch that was already recorded.
+fn apply_patch(
+    lua: &Lua,
+    mod_id: &str,
+    file: &str,
+    function: &str,
+    kind: PatchKind,
+    code_to_insert: &str,
+    order: Option<usize>,
+) -> LuaResult<usize> {
+    let function_code = get_function_code(lua, file, function)?;
+    match kind.edits(&function_code, code_to_insert) {
+        Ok(edits) => {
+            let new_code = apply_edits(&function_code, &edits);
+            if let Err(e) = set_function_code(lua, file, function, new_code.clone()) {
+                let excerpts: Vec<String> = edited_regions(&edits)
+                    .into_iter()
+                    .map(|region| excerpt(&new_code, region, 40))
+                    .collect();
+                return Err(mlua::Error::RuntimeError(format!(
+                    "Patch of {} breaks {}/{}: {}\n{}",
+                    mod_id,
+                    file,
+                    function,
+                    e,
+                    excerpts.join("\n")
+                )));
+            }
+            let conflicts = with_registry(lua, |registry| {
+                registry.keep_original(file, function, &function_code);
+                registry.record(mod_id, file, function, kind, code_to_insert, &edits, order)
+            });
+            for conflict in conflicts {
+                println!("Patch conflict in {}: {}", file, conflict.message);
+            }
+            Ok(edits.len())
+        }
+        Err(e) => {
+            let others = with_registry(lua, |registry| {
+                registry.record_failure(mod_id, file, function, &e);
+                registry.patched_by(file, function, mod_id)
+            });
+            let mut message = format!("{} in {}/{}", e, file, function);
+            if !others.is_empty() {
+                message = format!("{} (already patched by {})", message, others.join(", "));
+            }
+            Err(mlua::Error::RuntimeError(message))
+        }
+    }
+}
+
+// Restores the original source of a patched function, returns the number of patches removed
+pub fn revert(lua: &Lua, file: String, function: String) -> LuaResult<usize> {
+    let (original, patches) = with_registry(lua, |registry| registry.forget(&file, &function));
+    if let Some(original) = original {
+        set_function_code(lua, &file, &function, original)?;
+    }
+    Ok(patches.len())
+}
+
+// Removes every patch of `mod_id`: the functions it touched are restored and the patches of
+// other mods are applied again in their original order. Returns the number of patches removed.
+pub fn revert_mod(lua: &Lua, mod_id: String) -> LuaResult<usize> {
+    let functions = with_registry(lua, |registry| registry.functions_patched_by(&mod_id));
+    let mut removed = 0;
+    for (file, function) in functions {
+        let (original, patches) = with_registry(lua, |registry| registry.forget(&file, &function));
+        if let Some(original) = original {
+            set_function_code(lua, &file, &function, original)?;
+        }
+        for patch in patches {
+            if patch.mod_id == mod_id {
+                removed += 1;
+                continue;
+            }
+            let order = Some(patch.order);
+            let replayed = apply_patch(
+                lua,
+                &patch.mod_id,
+                &file,
+                &function,
+                patch.kind,
+                &patch.insert,
+                order,
+            );
+            if let Err(e) = replayed {
+                println!("Could not reapply patch of {}: {}", patch.mod_id, e);
+            }
+        }
+    }
+    with_registry(lua, |registry| registry.forget_conflicts_of(&mod_id));
+    Ok(removed)
+}
+
+fn get_function_code(lua: &Lua, file: &str, function: &str) -> LuaResult<String> {
+    lua.load(format!("return game_state['{}']['{}']", file, function).as_str())
+        .eval::<String>()
+}
+
+// Compiles and runs the new code, and only once the function it defines has its upvalues
+// stores the code in game_state. When a step fails the previous function is put back.
+fn set_function_code(lua: &Lua, file: &str, function: &str, new_code: String) -> LuaResult<()> {
+    let file_table = lua
+        .load(format!("return game_state['{}']", file).as_str())
+        .eval::<Table>()?;
+    let current = resolve_function(lua, function);
+    if let Err(e) = eval_function_code(lua, file, function, &new_code) {
+        let current = current.map_or(Value::Nil, Value::Function);
+        assign_function(lua, function, current)?;
+        return Err(e);
+    }
+    file_table.set(function, new_code)
+}
 
-    let function_code = lua
-        .load(format!("return game_state['{}']['{}']", file, function).as_str())
-        .eval::<String>()?;
+// Redefines `function` from `code` so that it keeps the upvalues of the game's definition.
+// Functions defined in a game file close over the file's locals, which a global chunk cannot
+// see: the names are declared as locals of the chunk and the new closure's upvalues are joined
+// to the ones of the original function. A mod wrapping the global has its own upvalues, so they
+// are never taken from the current value of the global.
+fn eval_function_code(lua: &Lua, file: &str, function: &str, code: &str) -> LuaResult<()> {
+    let chunk_name = format!("={}/{}", file, function);
+    let debug = lua.globals().get::<_, Option<Table>>("debug")?;
+    let original = match (&debug, code.starts_with("local ")) {
+        (Some(_), false) => original_function(lua, file, function)?,
+        _ => None,
+    };
+    let (debug, original) = match (debug, original) {
+        (Some(debug), Some(original)) => (debug, original),
+        _ => {
+            let chunk = lua.load(code).set_name(chunk_name).into_function()?;
+            return chunk.call(());
+        }
+    };
 
-    if function_code.contains(&code_to_find) {
-        let new_code = function_code.replace(&code_to_find, &code_to_insert);
-        let file_table = lua
-            .load(format!("return game_state['{}']", file).as_str())
-            .eval::<Table>()?;
-        file_table.set(function.clone(), new_code.clone())?;
+    // upvalues by name, as (index, value), C functions have unnamed upvalues
+    let pool: HashMap<String, (usize, Value)> = upvalues(&debug, &original)?
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (name, _))| !name.is_empty())
+        .map(|(index, (name, value))| (name, (index + 1, value)))
+        .collect();
 
-        // overwrite the old function in a pcall
-        lua.load(format!("pcall(function() {} end)", new_code).as_str())
-            .exec()?;
+    // only the names the new code refers to, Lua limits the number of locals of a chunk
+    let referenced: HashSet<String> = match tokenize(code) {
+        Ok(tokens) => tokens
+            .into_iter()
+            .filter_map(|token| match token.kind {
+                TokenKind::Name(name) => Some(name),
+                _ => None,
+            })
+            .collect(),
+        Err(_) => HashSet::new(),
+    };
+    let mut locals: Vec<&String> = pool
+        .keys()
+        .filter(|name| referenced.contains(*name))
+        .collect();
+    locals.sort();
+    let prelude = if locals.is_empty() {
+        String::new()
+    } else {
+        let names: Vec<&str> = locals.iter().map(|name| name.as_str()).collect();
+        format!("local {}; ", names.join(", "))
+    };
+    let chunk = lua
+        .load(format!("{}{}", prelude, code).as_str())
+        .set_name(chunk_name)
+        .into_function()?;
+    let current = resolve_function(lua, function);
+    chunk.call::<_, ()>(())?;
 
-        return Ok(());
+    let new = match resolve_function(lua, function) {
+        Some(new) if Some(&new) != current.as_ref() => new,
+        _ => return Ok(()),
+    };
+    let upvalue_join = debug.get::<_, Option<Function>>("upvaluejoin")?;
+    let set_upvalue = debug.get::<_, Function>("setupvalue")?;
+    for (index, (name, _)) in upvalues(&debug, &new)?.into_iter().enumerate() {
+        if let Some((source_index, value)) = pool.get(&name) {
+            match &upvalue_join {
+                // shares the variable, later assignments in the file stay visible
+                Some(join) => {
+                    join.call::<_, ()>((new.clone(), index + 1, original.clone(), *source_index))?
+                }
+                // plain Lua 5.1 can only copy the current value
+                None => set_upvalue.call::<_, ()>((new.clone(), index + 1, value.clone()))?,
+            }
+        }
+    }
+    let getfenv = lua.globals().get::<_, Option<Function>>("getfenv")?;
+    let setfenv = lua.globals().get::<_, Option<Function>>("setfenv")?;
+    if let (Some(getfenv), Some(setfenv)) = (getfenv, setfenv) {
+        let env: Value = getfenv.call(original)?;
+        setfenv.call::<_, ()>((new, env))?;
+    }
+    Ok(())
+}
+
+const ORIGINAL_FUNCTIONS: &str = "balalib_original_functions";
+
+// The function `file` defined as `function`, as it was the first time it was looked up. The
+// registry keeps it alive once patches and mods replaced the global.
+fn original_function<'lua>(
+    lua: &'lua Lua,
+    file: &str,
+    function: &str,
+) -> LuaResult<Option<Function<'lua>>> {
+    let originals = match lua.named_registry_value::<Option<Table>>(ORIGINAL_FUNCTIONS)? {
+        Some(originals) => originals,
+        None => {
+            let originals = lua.create_table()?;
+            lua.set_named_registry_value(ORIGINAL_FUNCTIONS, originals.clone())?;
+            originals
+        }
+    };
+    let key = format!("{}/{}", file, function);
+    if let Some(original) = originals.raw_get::<_, Option<Function>>(key.as_str())? {
+        return Ok(Some(original));
+    }
+    let original = resolve_function(lua, function);
+    if let Some(original) = &original {
+        originals.raw_set(key, original.clone())?;
+    }
+    Ok(original)
+}
+
+// Looks up a global function by its qualified name, e.g. `Card:calculate_joker`
+pub fn resolve_function<'lua>(lua: &'lua Lua, name: &str) -> Option<Function<'lua>> {
+    let mut value = Value::Table(lua.globals());
+    for part in name.split(['.', ':']) {
+        value = match value {
+            Value::Table(table) => table.raw_get(part).ok()?,
+            _ => return None,
+        };
+    }
+    match value {
+        Value::Function(function) => Some(function),
+        _ => None,
+    }
+}
+
+// Sets the global function named like resolve_function looks it up, if its table exists
+fn assign_function<'lua>(lua: &'lua Lua, name: &str, function: Value<'lua>) -> LuaResult<()> {
+    let parts: Vec<&str> = name.split(['.', ':']).collect();
+    let (last, path) = parts.split_last().unwrap();
+    let mut table = lua.globals();
+    for part in path {
+        table = match table.raw_get::<_, Value>(*part)? {
+            Value::Table(child) => child,
+            _ => return Ok(()),
+        };
     }
+    table.raw_set(*last, function)
+}
 
-    Err(mlua::Error::RuntimeError("Code not found".to_string()))
+fn upvalues<'lua>(
+    debug: &Table<'lua>,
+    function: &Function<'lua>,
+) -> LuaResult<Vec<(String, Value<'lua>)>> {
+    let get_upvalue = debug.get::<_, Function>("getupvalue")?;
+    let mut upvalues = Vec::new();
+    loop {
+        let (name, value): (Option<String>, Value) =
+            get_upvalue.call((function.clone(), upvalues.len() + 1))?;
+        match name {
+            Some(name) => upvalues.push((name, value)),
+            None => return Ok(upvalues),
+        }
+    }
 }
 
 pub fn validate_schema(schema: String, data: String) -> LuaResult<String> {
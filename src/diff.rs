@@ -0,0 +1,217 @@
+use crate::lua::lexer::{tokenize, TokenKind};
+use crate::patches::installed_patch_targets;
+use crate::persistence::write_atomic;
+use crate::sources::{build_source_index, load_source_index, read_lua_sources, SourceIndex};
+use crate::structs::functionchange::{ChangeKind, FunctionChange};
+use mlua::prelude::{LuaError, LuaResult};
+use mlua::Lua;
+use std::path::Path;
+
+const CONTEXT_LINES: usize = 3;
+// beyond this many line pairs a change is shown as a whole removal and addition
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Compares two function indexes, e.g. the sources of two game versions. `targets` are the
+/// (mod id, file, function) of installed patches, used to fill in `patched_by`.
+///
+/// ```no_run
+/// let old = balalib::read_source_index("balatro-1.0.1.json").unwrap();
+/// let new = balalib::read_source_index("Balatro.exe").unwrap();
+/// for change in balalib::diff_sources(&old, &new, &[]) {
+///     println!("{} {}/{}", change.kind.as_str(), change.file, change.function);
+/// }
+/// ```
+pub fn diff_sources(
+    old: &SourceIndex,
+    new: &SourceIndex,
+    targets: &[(String, String, String)],
+) -> Vec<FunctionChange> {
+    let empty = Default::default();
+    let mut changes = Vec::new();
+    let mut files: Vec<&String> = old.keys().chain(new.keys()).collect();
+    files.sort();
+    files.dedup();
+    for file in files {
+        let old_functions = old.get(file).unwrap_or(&empty);
+        let new_functions = new.get(file).unwrap_or(&empty);
+        let mut functions: Vec<&String> =
+            old_functions.keys().chain(new_functions.keys()).collect();
+        functions.sort();
+        functions.dedup();
+        for function in functions {
+            let (kind, diff) = match (old_functions.get(function), new_functions.get(function)) {
+                (Some(old_code), Some(new_code)) if old_code == new_code => continue,
+                (Some(old_code), Some(new_code)) => (
+                    ChangeKind::Changed,
+                    Some(unified_diff(
+                        &format!("{}/{}", file, function),
+                        &diff_lines(old_code),
+                        &diff_lines(new_code),
+                    )),
+                ),
+                (Some(_), None) => (ChangeKind::Removed, None),
+                _ => (ChangeKind::Added, None),
+            };
+            let mut patched_by: Vec<String> = targets
+                .iter()
+                .filter(|(_, target_file, target_function)| {
+                    target_file == file && target_function == function
+                })
+                .map(|(mod_id, _, _)| mod_id.clone())
+                .collect();
+            patched_by.sort();
+            patched_by.dedup();
+            changes.push(FunctionChange {
+                kind,
+                file: file.clone(),
+                function: function.clone(),
+                diff,
+                patched_by,
+            });
+        }
+    }
+    changes
+}
+
+// Lines to compare a function by. game_state holds minified single-line code, which is broken
+// before statement keywords so that diffs point at the statement that changed.
+pub fn diff_lines(code: &str) -> Vec<String> {
+    if code.contains('\n') {
+        return code.lines().map(|line| line.to_string()).collect();
+    }
+    let tokens = match tokenize(code) {
+        Ok(tokens) => tokens,
+        Err(_) => return vec![code.to_string()],
+    };
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    for token in tokens.iter() {
+        let breaks = matches!(
+            token.kind,
+            TokenKind::Keyword(
+                "local"
+                    | "if"
+                    | "for"
+                    | "while"
+                    | "repeat"
+                    | "return"
+                    | "end"
+                    | "else"
+                    | "elseif"
+                    | "until"
+                    | "break"
+            )
+        );
+        if breaks && token.span.start > line_start {
+            lines.push(code[line_start..token.span.start].trim().to_string());
+            line_start = token.span.start;
+        }
+    }
+    lines.push(code[line_start..].trim().to_string());
+    lines.retain(|line| !line.is_empty());
+    lines
+}
+
+// Unified diff of two line sequences with three lines of context
+pub fn unified_diff(name: &str, old: &[String], new: &[String]) -> String {
+    // (tag, old index, new index) for every line, tags are ' ', '-' and '+'
+    let mut ops: Vec<(char, usize, usize)> = Vec::new();
+    if old.len().saturating_mul(new.len()) > MAX_DIFF_CELLS {
+        ops.extend((0..old.len()).map(|i| ('-', i, 0)));
+        ops.extend((0..new.len()).map(|j| ('+', old.len(), j)));
+    } else {
+        // longest common subsequence lengths of the suffixes
+        let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+        for i in (0..old.len()).rev() {
+            for j in (0..new.len()).rev() {
+                lcs[i][j] = if old[i] == new[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < old.len() || j < new.len() {
+            if i < old.len() && j < new.len() && old[i] == new[j] {
+                ops.push((' ', i, j));
+                i += 1;
+                j += 1;
+            } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+                // removals before additions, like diff -u
+                ops.push(('-', i, j));
+                i += 1;
+            } else {
+                ops.push(('+', i, j));
+                j += 1;
+            }
+        }
+    }
+
+    let mut diff = format!("--- a/{}\n+++ b/{}\n", name, name);
+    let changed: Vec<usize> = (0..ops.len()).filter(|&k| ops[k].0 != ' ').collect();
+    let mut k = 0;
+    while k < changed.len() {
+        // grow the hunk while the next change is within the context of this one
+        let start = changed[k].saturating_sub(CONTEXT_LINES);
+        let mut end = changed[k];
+        while k < changed.len() && changed[k] <= end + 2 * CONTEXT_LINES {
+            end = changed[k];
+            k += 1;
+        }
+        let end = (end + CONTEXT_LINES + 1).min(ops.len());
+        let hunk = &ops[start..end];
+        let old_count = hunk.iter().filter(|op| op.0 != '+').count();
+        let new_count = hunk.iter().filter(|op| op.0 != '-').count();
+        let old_start = hunk[0].1 + usize::from(old_count > 0);
+        let new_start = hunk[0].2 + usize::from(new_count > 0);
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start, old_count, new_start, new_count
+        ));
+        for (tag, i, j) in hunk.iter() {
+            let line = match tag {
+                '+' => &new[*j],
+                _ => &old[*i],
+            };
+            diff.push_str(&format!("{}{}\n", tag, line));
+        }
+    }
+    diff
+}
+
+/// Reads the function index of a snapshot written by snapshot_sources (a .json file), or of a
+/// game executable, .love archive or folder
+pub fn read_source_index(path: &str) -> Result<SourceIndex, String> {
+    if !path.ends_with(".json") {
+        let files = read_lua_sources(Path::new(path)).map_err(|e| e.to_string())?;
+        return Ok(build_source_index(files));
+    }
+    let snapshot = std::fs::read_to_string(path)
+        .map_err(|e| format!("Error reading snapshot {}: {}", path, e))?;
+    serde_json::from_str(&snapshot).map_err(|e| format!("Error parsing snapshot {}: {}", path, e))
+}
+
+// Saves the unpatched sources of the running game, to diff them against a later version
+pub fn snapshot_sources(lua: &Lua, path: String) -> LuaResult<()> {
+    let index = load_source_index(lua)?;
+    let snapshot = serde_json::to_string(&index)
+        .map_err(|e| LuaError::RuntimeError(format!("Error serializing snapshot: {}", e)))?;
+    write_atomic(&path, snapshot)?;
+    Ok(())
+}
+
+// Diffs the sources at `old` against the ones at `new`, or against the running game, see
+// read_source_index for the accepted paths
+pub fn diff_game_sources(
+    lua: &Lua,
+    old: String,
+    new: Option<String>,
+) -> LuaResult<Vec<FunctionChange>> {
+    let old = read_source_index(&old).map_err(LuaError::RuntimeError)?;
+    let new = match new {
+        Some(new) => read_source_index(&new).map_err(LuaError::RuntimeError)?,
+        None => load_source_index(lua)?,
+    };
+    Ok(diff_sources(&old, &new, &installed_patch_targets(lua)?))
+}
@@ -0,0 +1,312 @@
+use crate::serialization::{
+    encode_bytes, json_value_to_lua_value, path_segment, sorted_keys, special_number, tag, tagged,
+    to_json_value, ConversionError, JsonKey, Tagged, BYTES_TAG, NUMBER_TAG,
+};
+use crate::structs::serializeoptions::SerializeOptions;
+use mlua::prelude::{LuaError, LuaResult, LuaString};
+use mlua::{Lua, Value};
+use serde_json::Value as JsonValue;
+
+// TOML, YAML and MessagePack go through the JSON values of serialization.rs, so they follow the
+// same conversion rules and report errors with the same paths. Where a format has a native type
+// for a tagged value it is used instead: floats for NaN and infinities in all three, binary for
+// non-UTF-8 strings in MessagePack.
+
+// A float as JSON, tagged when JSON can't hold it
+fn float_value(number: f64) -> JsonValue {
+    match serde_json::Number::from_f64(number) {
+        Some(number) => JsonValue::Number(number),
+        None => tag(
+            NUMBER_TAG,
+            special_number(number).unwrap_or("NaN").to_string(),
+        ),
+    }
+}
+
+fn index_segment(index: usize) -> String {
+    path_segment(&JsonKey::Index(index as i64 + 1))
+}
+
+fn key_segment(key: &str) -> String {
+    path_segment(&JsonKey::parse(key))
+}
+
+fn with_options(value: &JsonValue, options: &SerializeOptions) -> JsonValue {
+    if options.sort_keys {
+        sorted_keys(value.clone())
+    } else {
+        value.clone()
+    }
+}
+
+pub fn json_value_to_toml(value: &JsonValue) -> Result<toml::Value, ConversionError> {
+    match value {
+        JsonValue::Null => Err(ConversionError::new("TOML has no null")),
+        JsonValue::Bool(b) => Ok(toml::Value::Boolean(*b)),
+        JsonValue::Number(n) => match n.as_i64() {
+            Some(i) => Ok(toml::Value::Integer(i)),
+            None => Ok(toml::Value::Float(n.as_f64().unwrap_or(f64::NAN))),
+        },
+        JsonValue::String(s) => Ok(toml::Value::String(s.clone())),
+        JsonValue::Array(items) => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| json_value_to_toml(item).map_err(|e| e.within(index_segment(i))))
+            .collect::<Result<Vec<_>, _>>()
+            .map(toml::Value::Array),
+        JsonValue::Object(map) => {
+            if let Some(Tagged::Number(n)) = tagged(map) {
+                return Ok(toml::Value::Float(n));
+            }
+            let mut table = toml::Table::new();
+            for (key, value) in map {
+                let value = json_value_to_toml(value).map_err(|e| e.within(key_segment(key)))?;
+                table.insert(key.clone(), value);
+            }
+            Ok(toml::Value::Table(table))
+        }
+    }
+}
+
+pub fn toml_to_json_value(value: toml::Value) -> JsonValue {
+    match value {
+        toml::Value::String(s) => JsonValue::String(s),
+        toml::Value::Integer(i) => JsonValue::from(i),
+        toml::Value::Float(f) => float_value(f),
+        toml::Value::Boolean(b) => JsonValue::Bool(b),
+        // dates have no Lua counterpart, they're read as their TOML text
+        toml::Value::Datetime(d) => JsonValue::String(d.to_string()),
+        toml::Value::Array(items) => {
+            JsonValue::Array(items.into_iter().map(toml_to_json_value).collect())
+        }
+        toml::Value::Table(table) => JsonValue::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json_value(value)))
+                .collect(),
+        ),
+    }
+}
+
+// TOML keys are always sorted, indent only picks the pretty output
+pub fn write_toml(value: &JsonValue, options: &SerializeOptions) -> Result<String, String> {
+    let value = json_value_to_toml(value).map_err(|e| e.to_string())?;
+    if !value.is_table() {
+        return Err("TOML needs a table at the root".to_string());
+    }
+    match options.indent {
+        Some(_) => toml::to_string_pretty(&value),
+        None => toml::to_string(&value),
+    }
+    .map_err(|e| e.to_string())
+}
+
+pub fn parse_toml(text: &str) -> Result<JsonValue, String> {
+    let table: toml::Table = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+    Ok(toml_to_json_value(toml::Value::Table(table)))
+}
+
+pub fn json_value_to_yaml(value: &JsonValue) -> serde_yaml::Value {
+    match value {
+        JsonValue::Null => serde_yaml::Value::Null,
+        JsonValue::Bool(b) => serde_yaml::Value::Bool(*b),
+        JsonValue::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => serde_yaml::Value::from(i),
+            (None, Some(u)) => serde_yaml::Value::from(u),
+            _ => serde_yaml::Value::from(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        JsonValue::String(s) => serde_yaml::Value::String(s.clone()),
+        JsonValue::Array(items) => {
+            serde_yaml::Value::Sequence(items.iter().map(json_value_to_yaml).collect())
+        }
+        JsonValue::Object(map) => {
+            if let Some(Tagged::Number(n)) = tagged(map) {
+                return serde_yaml::Value::from(n);
+            }
+            // sparse array indices stay integer keys
+            map.iter()
+                .map(|(key, value)| {
+                    let key = match JsonKey::parse(key) {
+                        JsonKey::Index(index) => serde_yaml::Value::from(index),
+                        JsonKey::Name(name) => serde_yaml::Value::String(name),
+                    };
+                    (key, json_value_to_yaml(value))
+                })
+                .collect::<serde_yaml::Mapping>()
+                .into()
+        }
+    }
+}
+
+pub fn yaml_to_json_value(value: serde_yaml::Value) -> Result<JsonValue, ConversionError> {
+    match value {
+        serde_yaml::Value::Null => Ok(JsonValue::Null),
+        serde_yaml::Value::Bool(b) => Ok(JsonValue::Bool(b)),
+        serde_yaml::Value::Number(n) => Ok(match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => JsonValue::from(i),
+            (None, Some(u)) => JsonValue::from(u),
+            _ => float_value(n.as_f64().unwrap_or(f64::NAN)),
+        }),
+        serde_yaml::Value::String(s) => Ok(JsonValue::String(s)),
+        serde_yaml::Value::Sequence(items) => items
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| yaml_to_json_value(item).map_err(|e| e.within(index_segment(i))))
+            .collect::<Result<Vec<_>, _>>()
+            .map(JsonValue::Array),
+        serde_yaml::Value::Mapping(mapping) => {
+            let mut map = serde_json::Map::new();
+            for (key, value) in mapping {
+                let key = match key {
+                    serde_yaml::Value::String(s) => s,
+                    serde_yaml::Value::Number(n) if n.is_i64() || n.is_u64() => n.to_string(),
+                    _ => return Err(ConversionError::new("Unsupported YAML key")),
+                };
+                let value = yaml_to_json_value(value).map_err(|e| e.within(key_segment(&key)))?;
+                map.insert(key, value);
+            }
+            Ok(JsonValue::Object(map))
+        }
+        // custom tags are dropped, the tagged value is kept
+        serde_yaml::Value::Tagged(tagged) => yaml_to_json_value(tagged.value),
+    }
+}
+
+pub fn write_yaml(value: &JsonValue, options: &SerializeOptions) -> Result<String, String> {
+    let value = json_value_to_yaml(&with_options(value, options));
+    serde_yaml::to_string(&value).map_err(|e| e.to_string())
+}
+
+pub fn parse_yaml(text: &str) -> Result<JsonValue, String> {
+    let value: serde_yaml::Value = serde_yaml::from_str(text).map_err(|e| e.to_string())?;
+    yaml_to_json_value(value).map_err(|e| e.to_string())
+}
+
+pub fn json_value_to_msgpack(value: &JsonValue) -> rmpv::Value {
+    match value {
+        JsonValue::Null => rmpv::Value::Nil,
+        JsonValue::Bool(b) => rmpv::Value::Boolean(*b),
+        JsonValue::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => rmpv::Value::from(i),
+            (None, Some(u)) => rmpv::Value::from(u),
+            _ => rmpv::Value::F64(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        JsonValue::String(s) => rmpv::Value::from(s.as_str()),
+        JsonValue::Array(items) => {
+            rmpv::Value::Array(items.iter().map(json_value_to_msgpack).collect())
+        }
+        JsonValue::Object(map) => match tagged(map) {
+            Some(Tagged::Number(n)) => rmpv::Value::F64(n),
+            Some(Tagged::Bytes(bytes)) => rmpv::Value::Binary(bytes),
+            None => rmpv::Value::Map(
+                map.iter()
+                    .map(|(key, value)| {
+                        let key = match JsonKey::parse(key) {
+                            JsonKey::Index(index) => rmpv::Value::from(index),
+                            JsonKey::Name(name) => rmpv::Value::from(name),
+                        };
+                        (key, json_value_to_msgpack(value))
+                    })
+                    .collect(),
+            ),
+        },
+    }
+}
+
+pub fn msgpack_to_json_value(value: rmpv::Value) -> Result<JsonValue, ConversionError> {
+    match value {
+        rmpv::Value::Nil => Ok(JsonValue::Null),
+        rmpv::Value::Boolean(b) => Ok(JsonValue::Bool(b)),
+        rmpv::Value::Integer(i) => match (i.as_i64(), i.as_u64()) {
+            (Some(i), _) => Ok(JsonValue::from(i)),
+            (None, Some(u)) => Ok(JsonValue::from(u)),
+            _ => Err(ConversionError::new("Unsupported MessagePack integer")),
+        },
+        rmpv::Value::F32(f) => Ok(float_value(f as f64)),
+        rmpv::Value::F64(f) => Ok(float_value(f)),
+        rmpv::Value::String(s) => Ok(match s.as_str() {
+            Some(s) => JsonValue::String(s.to_string()),
+            None => tag(BYTES_TAG, encode_bytes(s.as_bytes())),
+        }),
+        rmpv::Value::Binary(bytes) => Ok(tag(BYTES_TAG, encode_bytes(&bytes))),
+        rmpv::Value::Array(items) => items
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| msgpack_to_json_value(item).map_err(|e| e.within(index_segment(i))))
+            .collect::<Result<Vec<_>, _>>()
+            .map(JsonValue::Array),
+        rmpv::Value::Map(entries) => {
+            let mut map = serde_json::Map::new();
+            for (key, value) in entries {
+                let key = match key {
+                    rmpv::Value::String(s) if s.is_str() => s.into_str().unwrap_or_default(),
+                    rmpv::Value::Integer(i) => i.to_string(),
+                    _ => return Err(ConversionError::new("Unsupported MessagePack key")),
+                };
+                let value =
+                    msgpack_to_json_value(value).map_err(|e| e.within(key_segment(&key)))?;
+                map.insert(key, value);
+            }
+            Ok(JsonValue::Object(map))
+        }
+        rmpv::Value::Ext(..) => Err(ConversionError::new("Unsupported MessagePack extension")),
+    }
+}
+
+pub fn write_msgpack(value: &JsonValue, options: &SerializeOptions) -> Result<Vec<u8>, String> {
+    let value = json_value_to_msgpack(&with_options(value, options));
+    let mut bytes = Vec::new();
+    rmpv::encode::write_value(&mut bytes, &value).map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+pub fn parse_msgpack(bytes: &[u8]) -> Result<JsonValue, String> {
+    let value = rmpv::decode::read_value(&mut &bytes[..]).map_err(|e| e.to_string())?;
+    msgpack_to_json_value(value).map_err(|e| e.to_string())
+}
+
+fn convert(value: &Value, options: &SerializeOptions, format: &str) -> LuaResult<JsonValue> {
+    to_json_value(value, options)
+        .map_err(|e| LuaError::RuntimeError(format!("Error converting to {}: {}", format, e)))
+}
+
+pub fn lua_to_toml(value: Value, options: SerializeOptions) -> LuaResult<String> {
+    let json_value = convert(&value, &options, "TOML")?;
+    write_toml(&json_value, &options)
+        .map_err(|e| LuaError::RuntimeError(format!("Error converting to TOML: {}", e)))
+}
+
+pub fn toml_to_lua(lua: &Lua, toml: String) -> LuaResult<Value> {
+    let value = parse_toml(&toml)
+        .map_err(|e| LuaError::RuntimeError(format!("Error parsing TOML: {}", e)))?;
+    json_value_to_lua_value(lua, value)
+}
+
+pub fn lua_to_yaml(value: Value, options: SerializeOptions) -> LuaResult<String> {
+    let json_value = convert(&value, &options, "YAML")?;
+    write_yaml(&json_value, &options)
+        .map_err(|e| LuaError::RuntimeError(format!("Error converting to YAML: {}", e)))
+}
+
+pub fn yaml_to_lua(lua: &Lua, yaml: String) -> LuaResult<Value> {
+    let value = parse_yaml(&yaml)
+        .map_err(|e| LuaError::RuntimeError(format!("Error parsing YAML: {}", e)))?;
+    json_value_to_lua_value(lua, value)
+}
+
+pub fn lua_to_msgpack<'lua>(
+    lua: &'lua Lua,
+    value: Value,
+    options: SerializeOptions,
+) -> LuaResult<LuaString<'lua>> {
+    let json_value = convert(&value, &options, "MessagePack")?;
+    let bytes = write_msgpack(&json_value, &options)
+        .map_err(|e| LuaError::RuntimeError(format!("Error converting to MessagePack: {}", e)))?;
+    lua.create_string(bytes)
+}
+
+pub fn msgpack_to_lua<'lua>(lua: &'lua Lua, data: LuaString) -> LuaResult<Value<'lua>> {
+    let value = parse_msgpack(data.as_bytes())
+        .map_err(|e| LuaError::RuntimeError(format!("Error parsing MessagePack: {}", e)))?;
+    json_value_to_lua_value(lua, value)
+}
@@ -1,22 +1,53 @@
+use crate::commands::{complete_command, list_commands, register_commands, run_command};
 #[cfg(not(target_os = "android"))]
 use crate::core::restart;
 use crate::core::{
-    inject, is_mod_present, json_to_lua, lua_to_json, need_update, setup_injection, validate_schema,
+    inject, inject_at, is_mod_present, need_update, revert, revert_mod, setup_injection,
+    validate_schema,
 };
+use crate::diff::{diff_game_sources, snapshot_sources};
+use crate::formats::{
+    lua_to_msgpack, lua_to_toml, lua_to_yaml, msgpack_to_lua, toml_to_lua, yaml_to_lua,
+};
+use crate::localization::load_localization;
+use crate::lua::patch::{AstTarget, TextTarget};
+use crate::overlay::{load_overlays, overlay_files, overlay_register, overlay_resolve};
+use crate::patches::{apply_patch_files, list_patches, patch_conflicts};
+use crate::persistence::write_save_file;
+use crate::serialization::{json_to_lua, lua_to_json};
+use crate::sources::game_version;
 use mlua::prelude::*;
 use mlua::Value;
 use structs::modinfo::ModInfo;
+use structs::serializeoptions::SerializeOptions;
 
 use crate::mods::*;
 #[cfg(not(target_os = "android"))]
 use crate::updater::{get_latest_cli_version, self_update};
+use crate::watcher::watch_mods;
 
+mod commands;
+mod config;
 mod core;
+mod diff;
+mod formats;
+mod localization;
+mod lua;
 mod mods;
+mod overlay;
+mod patches;
+mod persistence;
+mod serialization;
+mod sources;
 mod structs;
 mod tests;
 mod updater;
 mod utils;
+mod watcher;
+
+pub use crate::diff::{diff_sources, read_source_index};
+pub use crate::sources::{read_lua_sources, SourceError, SourceIndex};
+pub use crate::structs::functionchange::{ChangeKind, FunctionChange};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -24,8 +55,8 @@ fn echo(_: &Lua, name: String) -> LuaResult<String> {
     Ok(name)
 }
 
-#[mlua::lua_module]
-fn balalib(lua: &Lua) -> LuaResult<LuaTable> {
+#[cfg_attr(feature = "module", mlua::lua_module)]
+pub fn balalib(lua: &Lua) -> LuaResult<LuaTable> {
     let exports = lua.create_table()?;
     exports.set("echo", lua.create_function(echo)?)?;
     exports.set("fetch_mods", lua.create_function(|_, ()| fetch_mods())?)?;
@@ -33,6 +64,18 @@ fn balalib(lua: &Lua) -> LuaResult<LuaTable> {
         "get_local_mods",
         lua.create_function(|lua, ()| get_local_mods(lua))?,
     )?;
+    exports.set(
+        "check_updates",
+        lua.create_function(|lua, mods: Option<Vec<ModInfo>>| {
+            check_updates(lua, mods.map_or_else(fetch_mods, Ok)?)
+        })?,
+    )?;
+    exports.set(
+        "update_all",
+        lua.create_function(|lua, mods: Option<Vec<ModInfo>>| {
+            update_all(lua, mods.map_or_else(fetch_mods, Ok)?)
+        })?,
+    )?;
     exports.set(
         "need_update",
         lua.create_function(|lua, ()| need_update(lua, ()))?,
@@ -43,12 +86,50 @@ fn balalib(lua: &Lua) -> LuaResult<LuaTable> {
     )?;
     exports.set(
         "lua_to_json",
-        lua.create_function(|_, table: Value| lua_to_json(table))?,
+        lua.create_function(|_, (table, options): (Value, SerializeOptions)| {
+            lua_to_json(table, options)
+        })?,
     )?;
     exports.set(
         "json_to_lua",
         lua.create_function(|lua, json: String| json_to_lua(lua, json))?,
     )?;
+    exports.set(
+        "lua_to_toml",
+        lua.create_function(|_, (value, options): (Value, SerializeOptions)| {
+            lua_to_toml(value, options)
+        })?,
+    )?;
+    exports.set(
+        "toml_to_lua",
+        lua.create_function(|lua, toml: String| toml_to_lua(lua, toml))?,
+    )?;
+    exports.set(
+        "lua_to_yaml",
+        lua.create_function(|_, (value, options): (Value, SerializeOptions)| {
+            lua_to_yaml(value, options)
+        })?,
+    )?;
+    exports.set(
+        "yaml_to_lua",
+        lua.create_function(|lua, yaml: String| yaml_to_lua(lua, yaml))?,
+    )?;
+    exports.set(
+        "lua_to_msgpack",
+        lua.create_function(|lua, (value, options): (Value, SerializeOptions)| {
+            lua_to_msgpack(lua, value, options)
+        })?,
+    )?;
+    exports.set(
+        "msgpack_to_lua",
+        lua.create_function(|lua, data: LuaString| msgpack_to_lua(lua, data))?,
+    )?;
+    exports.set(
+        "write_save_file",
+        lua.create_function(|lua, (name, contents): (String, LuaString)| {
+            write_save_file(lua, name, contents)
+        })?,
+    )?;
     exports.set(
         "is_mod_present",
         lua.create_function(|lua, mod_info: ModInfo| is_mod_present(lua, mod_info))?,
@@ -68,7 +149,121 @@ fn balalib(lua: &Lua) -> LuaResult<LuaTable> {
         "validate_schema",
         lua.create_function(|_, (schema, data): (String, String)| validate_schema(schema, data))?,
     )?;
-    exports.set("inject", lua.create_function(|lua, (file, function, code_to_find, code_to_insert): (String, String, String, String)| inject(lua, file, function, code_to_find, code_to_insert))?)?;
+    exports.set(
+        "inject",
+        lua.create_function(
+            |lua,
+             (file, function, code_to_find, code_to_insert, target, mod_id): (
+                String,
+                String,
+                String,
+                String,
+                TextTarget,
+                Option<String>,
+            )| {
+                inject(
+                    lua,
+                    file,
+                    function,
+                    code_to_find,
+                    code_to_insert,
+                    target,
+                    mod_id,
+                )
+            },
+        )?,
+    )?;
+    exports.set(
+        "inject_at",
+        lua.create_function(
+            |lua,
+             (file, function, target, code_to_insert, mod_id): (
+                String,
+                String,
+                AstTarget,
+                String,
+                Option<String>,
+            )| { inject_at(lua, file, function, target, code_to_insert, mod_id) },
+        )?,
+    )?;
+    exports.set(
+        "game_version",
+        lua.create_function(|lua, ()| Ok(game_version(lua)))?,
+    )?;
+    exports.set(
+        "snapshot_sources",
+        lua.create_function(|lua, path: String| snapshot_sources(lua, path))?,
+    )?;
+    exports.set(
+        "diff_game_sources",
+        lua.create_function(|lua, (old, new): (String, Option<String>)| {
+            diff_game_sources(lua, old, new)
+        })?,
+    )?;
+    exports.set(
+        "apply_patch_files",
+        lua.create_function(|lua, mods: LuaTable| apply_patch_files(lua, mods))?,
+    )?;
+    exports.set(
+        "revert",
+        lua.create_function(|lua, (file, function): (String, String)| revert(lua, file, function))?,
+    )?;
+    exports.set(
+        "revert_mod",
+        lua.create_function(|lua, mod_id: String| revert_mod(lua, mod_id))?,
+    )?;
+    exports.set(
+        "list_patches",
+        lua.create_function(|lua, ()| Ok(list_patches(lua)))?,
+    )?;
+    exports.set(
+        "patch_conflicts",
+        lua.create_function(|lua, ()| Ok(patch_conflicts(lua)))?,
+    )?;
+    exports.set(
+        "register_commands",
+        lua.create_function(|lua, mods: LuaTable| register_commands(lua, mods))?,
+    )?;
+    exports.set(
+        "run_command",
+        lua.create_function(|lua, line: String| run_command(lua, line))?,
+    )?;
+    exports.set(
+        "list_commands",
+        lua.create_function(|lua, ()| Ok(list_commands(lua)))?,
+    )?;
+    exports.set(
+        "complete_command",
+        lua.create_function(|lua, line: String| Ok(complete_command(lua, line)))?,
+    )?;
+    exports.set(
+        "load_localization",
+        lua.create_function(|lua, (mods, lang): (LuaTable, Option<String>)| {
+            load_localization(lua, mods, lang)
+        })?,
+    )?;
+    exports.set(
+        "load_overlays",
+        lua.create_function(|lua, mods: LuaTable| load_overlays(lua, mods))?,
+    )?;
+    exports.set(
+        "overlay_register",
+        lua.create_function(|lua, (mod_id, path, file): (String, String, String)| {
+            overlay_register(lua, mod_id, path, file)
+        })?,
+    )?;
+    exports.set(
+        "overlay_resolve",
+        lua.create_function(|lua, path: String| Ok(overlay_resolve(lua, path)))?,
+    )?;
+    exports.set(
+        "overlay_files",
+        lua.create_function(|lua, ()| Ok(overlay_files(lua)))?,
+    )?;
+    exports.set(
+        "watch_mods",
+        lua.create_function(|lua, interval_ms: Option<u64>| watch_mods(lua, interval_ms))?,
+    )?;
     exports.set("version", VERSION)?;
     exports.set(
         "sort_mods",
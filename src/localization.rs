@@ -0,0 +1,219 @@
+use crate::core::get_love_dir;
+use crate::mods::load_order;
+use crate::serialization::{json_to_lua, lua_value_to_json_value};
+use crate::structs::localizationconflict::LocalizationConflict;
+use mlua::prelude::{LuaError, LuaResult, LuaTable};
+use mlua::{Lua, Table, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// folder of a mod holding its `<lang>.lua` or `<lang>.json` files
+const LOCALIZATION_DIR: &str = "localization";
+// language of the texts used for keys a mod did not translate
+pub const FALLBACK_LANG: &str = "en-us";
+
+// Tracks which mod set every text of a language, to report mods overriding each other
+#[derive(Debug)]
+pub struct LocaleMerge {
+    lang: String,
+    // dotted key -> mod that set it last
+    owners: HashMap<String, String>,
+    pub conflicts: Vec<LocalizationConflict>,
+}
+
+impl LocaleMerge {
+    pub fn new(lang: &str) -> LocaleMerge {
+        LocaleMerge {
+            lang: lang.to_string(),
+            owners: HashMap::new(),
+            conflicts: Vec::new(),
+        }
+    }
+
+    // Records that `mod_id` sets `key`. Setting a key another mod set is a conflict, unless
+    // both gave the same text.
+    pub fn claim(&mut self, key: &str, mod_id: &str, same_value: impl FnOnce() -> bool) {
+        let previous = self.owners.insert(key.to_string(), mod_id.to_string());
+        if let Some(other_mod_id) = previous {
+            if other_mod_id != mod_id && !same_value() {
+                self.conflicts.push(LocalizationConflict {
+                    lang: self.lang.clone(),
+                    key: key.to_string(),
+                    mod_id: mod_id.to_string(),
+                    other_mod_id,
+                });
+            }
+        }
+    }
+}
+
+// The locale file of a mod for `lang`, a .lua file is preferred over a .json one
+pub fn locale_file(mod_dir: &Path, lang: &str) -> Option<PathBuf> {
+    ["lua", "json"]
+        .iter()
+        .map(|ext| {
+            mod_dir
+                .join(LOCALIZATION_DIR)
+                .join(format!("{}.{}", lang, ext))
+        })
+        .find(|path| path.is_file())
+}
+
+// Reads a locale file: a Lua chunk returning a table, like the game's own, or a JSON object
+fn read_locale<'lua>(lua: &'lua Lua, path: &Path) -> LuaResult<Table<'lua>> {
+    let content = fs::read_to_string(path)?;
+    let value = if path.extension().is_some_and(|ext| ext == "json") {
+        json_to_lua(lua, content)?
+    } else {
+        lua.load(content)
+            .set_name(format!("@{}", path.display()))
+            .eval::<Value>()?
+    };
+    match value {
+        Value::Table(table) => Ok(table),
+        _ => Err(LuaError::RuntimeError(
+            "a locale file must return a table".to_string(),
+        )),
+    }
+}
+
+// Texts are strings, numbers, or sequences of lines; any other table is merged key by key
+fn is_section(value: &Value) -> bool {
+    matches!(value, Value::Table(table) if table.raw_len() == 0)
+}
+
+// Deep-merges `source` into `target`. With `fill_only`, texts already in `target` are kept.
+pub fn merge_locale(
+    lua: &Lua,
+    target: &Table,
+    source: &Table,
+    prefix: &str,
+    mod_id: &str,
+    fill_only: bool,
+    merge: &mut LocaleMerge,
+) -> LuaResult<()> {
+    for pair in source.clone().pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let name = match &key {
+            Value::String(name) => name.to_str()?.to_string(),
+            Value::Integer(index) => index.to_string(),
+            _ => continue,
+        };
+        let path = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}.{}", prefix, name)
+        };
+        let existing: Value = target.raw_get(key.clone())?;
+
+        if let Value::Table(section) = &value {
+            if is_section(&value) {
+                let target_section = match existing {
+                    Value::Table(table) if table.raw_len() == 0 => table,
+                    Value::Nil => lua.create_table()?,
+                    // a text of the game or of another mod, which a section can't replace
+                    _ => {
+                        if !fill_only {
+                            println!(
+                                "Localization {} of {} is not a section, keeping it",
+                                path, mod_id
+                            );
+                        }
+                        continue;
+                    }
+                };
+                target.raw_set(key, target_section.clone())?;
+                merge_locale(
+                    lua,
+                    &target_section,
+                    section,
+                    &path,
+                    mod_id,
+                    fill_only,
+                    merge,
+                )?;
+                continue;
+            }
+        }
+
+        if fill_only {
+            if existing == Value::Nil {
+                target.raw_set(key, value)?;
+            }
+            continue;
+        }
+        merge.claim(&path, mod_id, || {
+            let existing = lua_value_to_json_value(&existing).ok();
+            existing.is_some() && existing == lua_value_to_json_value(&value).ok()
+        });
+        target.raw_set(key, value)?;
+    }
+    Ok(())
+}
+
+// Merges the locale files of the enabled mods in `mods` (as returned by sort_mods) into
+// G.localization, in load order so that a mod loaded later wins. `lang` defaults to the game's
+// language; keys a mod only has in English are added in English. Returns the keys several mods
+// set to different texts.
+pub fn load_localization(
+    lua: &Lua,
+    mods: LuaTable,
+    lang: Option<String>,
+) -> LuaResult<Vec<LocalizationConflict>> {
+    let lang = match lang {
+        Some(lang) => lang,
+        None => lua
+            .load("G and G.SETTINGS and G.SETTINGS.language")
+            .eval::<Option<String>>()?
+            .unwrap_or(FALLBACK_LANG.to_string()),
+    };
+    let localization: Table = lua
+        .load("G and G.localization")
+        .eval::<Option<Table>>()?
+        .ok_or(LuaError::RuntimeError(
+            "G.localization is not loaded".to_string(),
+        ))?;
+    let love_dir = get_love_dir(lua)?;
+    let order = load_order(mods)?;
+
+    let mut merge = LocaleMerge::new(&lang);
+    let mut passes = vec![(lang.as_str(), false)];
+    if lang != FALLBACK_LANG {
+        passes.push((FALLBACK_LANG, true));
+    }
+    for (pass_lang, fill_only) in passes {
+        for mod_id in order.iter() {
+            let mod_dir = Path::new(&love_dir).join("mods").join(mod_id);
+            let path = match locale_file(&mod_dir, pass_lang) {
+                Some(path) => path,
+                None => continue,
+            };
+            let merged = read_locale(lua, &path).and_then(|locale| {
+                merge_locale(
+                    lua,
+                    &localization,
+                    &locale,
+                    "",
+                    mod_id,
+                    fill_only,
+                    &mut merge,
+                )
+            });
+            if let Err(e) = merged {
+                println!("Localization {} of {} not loaded: {}", pass_lang, mod_id, e);
+            }
+        }
+    }
+
+    for conflict in merge.conflicts.iter() {
+        println!(
+            "Localization {} of {} is overridden by {}",
+            conflict.key, conflict.other_mod_id, conflict.mod_id
+        );
+    }
+    // let the game parse the merged descriptions again
+    lua.load("if init_localization then init_localization() end")
+        .exec()?;
+    Ok(merge.conflicts)
+}
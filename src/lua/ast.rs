@@ -0,0 +1,258 @@
+use crate::lua::lexer::Span;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stats: Vec<Stat>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub kind: StatKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncName {
+    pub path: Vec<String>,
+    pub method: Option<String>,
+}
+
+impl FuncName {
+    pub fn qualified(&self) -> String {
+        match &self.method {
+            Some(method) => format!("{}:{}", self.path.join("."), method),
+            None => self.path.join("."),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatKind {
+    Local {
+        names: Vec<String>,
+        exprs: Vec<Expr>,
+    },
+    // function bodies and loop bounds are boxed, they would make every statement several
+    // times larger
+    LocalFunction {
+        name: String,
+        func: Box<FunctionBody>,
+    },
+    Function {
+        name: FuncName,
+        func: Box<FunctionBody>,
+    },
+    Assign {
+        targets: Vec<Expr>,
+        exprs: Vec<Expr>,
+    },
+    Call(Expr),
+    Do(Block),
+    While {
+        cond: Expr,
+        block: Block,
+    },
+    Repeat {
+        block: Block,
+        cond: Expr,
+    },
+    If {
+        branches: Vec<(Expr, Block)>,
+        else_block: Option<Block>,
+    },
+    NumericFor {
+        var: String,
+        start: Box<Expr>,
+        end: Box<Expr>,
+        step: Option<Box<Expr>>,
+        block: Block,
+    },
+    GenericFor {
+        names: Vec<String>,
+        exprs: Vec<Expr>,
+        block: Block,
+    },
+    Return(Vec<Expr>),
+    Break,
+    Goto(String),
+    Label(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionBody {
+    pub params: Vec<String>,
+    pub vararg: bool,
+    pub block: Block,
+    // from the `function` keyword (or the name for statements) to the closing `end`
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableField {
+    Named { key: String, value: Expr },
+    Indexed { key: Expr, value: Expr },
+    Positional(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Nil,
+    True,
+    False,
+    Vararg,
+    Number(String),
+    // raw source of the literal, including quotes or long brackets
+    String(String),
+    Function(FunctionBody),
+    Name(String),
+    Field {
+        obj: Box<Expr>,
+        name: String,
+    },
+    Index {
+        obj: Box<Expr>,
+        key: Box<Expr>,
+    },
+    Call {
+        func: Box<Expr>,
+        args: Vec<Expr>,
+    },
+    MethodCall {
+        obj: Box<Expr>,
+        method: String,
+        args: Vec<Expr>,
+    },
+    Table(Vec<TableField>),
+    BinOp {
+        op: &'static str,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    UnOp {
+        op: &'static str,
+        expr: Box<Expr>,
+    },
+    Paren(Box<Expr>),
+}
+
+impl Expr {
+    // Dotted name of a variable expression such as `G.FUNCS.foo` or `G.FUNCS["foo"]`
+    pub fn qualified_name(&self) -> Option<String> {
+        match &self.kind {
+            ExprKind::Name(name) => Some(name.clone()),
+            ExprKind::Field { obj, name } => Some(format!("{}.{}", obj.qualified_name()?, name)),
+            ExprKind::Index { obj, key } => {
+                let key = string_literal_value(key)?;
+                Some(format!("{}.{}", obj.qualified_name()?, key))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Value of a simple quoted string literal without escapes, used for table keys
+pub fn string_literal_value(expr: &Expr) -> Option<String> {
+    match &expr.kind {
+        ExprKind::String(raw) if raw.len() >= 2 && !raw.starts_with('[') => {
+            let inner = &raw[1..raw.len() - 1];
+            if inner.contains('\\') {
+                None
+            } else {
+                Some(inner.to_string())
+            }
+        }
+        _ => None,
+    }
+}
+
+impl Stat {
+    // Expressions directly owned by this statement, function bodies are not descended into
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match &self.kind {
+            StatKind::Local { exprs, .. } | StatKind::Return(exprs) => exprs.iter().collect(),
+            StatKind::Assign { targets, exprs } => targets.iter().chain(exprs.iter()).collect(),
+            StatKind::Call(expr) => vec![expr],
+            StatKind::While { cond, .. } | StatKind::Repeat { cond, .. } => vec![cond],
+            StatKind::If { branches, .. } => branches.iter().map(|(cond, _)| cond).collect(),
+            StatKind::NumericFor {
+                start, end, step, ..
+            } => {
+                let mut exprs = vec![start.as_ref(), end.as_ref()];
+                exprs.extend(step.as_deref());
+                exprs
+            }
+            StatKind::GenericFor { exprs, .. } => exprs.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Nested blocks of control flow statements, function bodies are not included
+    pub fn blocks(&self) -> Vec<&Block> {
+        match &self.kind {
+            StatKind::Do(block)
+            | StatKind::While { block, .. }
+            | StatKind::Repeat { block, .. }
+            | StatKind::NumericFor { block, .. }
+            | StatKind::GenericFor { block, .. } => vec![block],
+            StatKind::If {
+                branches,
+                else_block,
+            } => branches
+                .iter()
+                .map(|(_, block)| block)
+                .chain(else_block.iter())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Body of a `function name()` or `local function name()` statement
+    pub fn function_body(&self) -> Option<&FunctionBody> {
+        match &self.kind {
+            StatKind::Function { func, .. } | StatKind::LocalFunction { func, .. } => Some(func),
+            _ => None,
+        }
+    }
+}
+
+impl Expr {
+    // Direct sub-expressions, the body of a function expression is not included
+    pub fn children(&self) -> Vec<&Expr> {
+        match &self.kind {
+            ExprKind::Field { obj, .. } => vec![obj],
+            ExprKind::Index { obj, key } => vec![obj, key],
+            ExprKind::Call { func, args } => std::iter::once(&**func).chain(args.iter()).collect(),
+            ExprKind::MethodCall { obj, args, .. } => {
+                std::iter::once(&**obj).chain(args.iter()).collect()
+            }
+            ExprKind::Table(fields) => fields
+                .iter()
+                .flat_map(|field| match field {
+                    TableField::Named { value, .. } | TableField::Positional(value) => vec![value],
+                    TableField::Indexed { key, value } => vec![key, value],
+                })
+                .collect(),
+            ExprKind::BinOp { lhs, rhs, .. } => vec![lhs, rhs],
+            ExprKind::UnOp { expr, .. } | ExprKind::Paren(expr) => vec![expr],
+            _ => Vec::new(),
+        }
+    }
+
+    // Name of the function being called, e.g. `ease_dollars` or `G.E_MANAGER:add_event`
+    pub fn callee_name(&self) -> Option<String> {
+        match &self.kind {
+            ExprKind::Call { func, .. } => func.qualified_name(),
+            ExprKind::MethodCall { obj, method, .. } => {
+                Some(format!("{}:{}", obj.qualified_name()?, method))
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::lua::ast::{string_literal_value, Block, Expr, ExprKind, StatKind, TableField};
+use crate::lua::lexer::{LuaSyntaxError, Span};
+use crate::lua::parser::parse;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDef {
+    // fully-qualified name, e.g. `Card:calculate_joker`, `G.FUNCS.play_cards` or `SMODS.Joker.inject`
+    pub name: String,
+    // source that redefines the function when executed
+    pub code: String,
+    // byte range of the definition in the original source
+    pub span: Span,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub is_local: bool,
+}
+
+// Finds every named function defined at file scope: `function a.b:c()`, `local function f()`,
+// `x = function()`, `local x = function()` and functions stored in table constructors assigned
+// to a name. Functions nested inside other function bodies are not reachable by name and are
+// left out.
+pub fn find_functions(source: &str) -> Result<Vec<FunctionDef>, LuaSyntaxError> {
+    let chunk = parse(source)?;
+    let mut finder = FunctionFinder {
+        source,
+        functions: Vec::new(),
+    };
+    finder.block(&chunk);
+    Ok(finder.functions)
+}
+
+pub fn line_at(source: &str, offset: usize) -> usize {
+    source.as_bytes()[..offset.min(source.len())]
+        .iter()
+        .filter(|b| **b == b'\n')
+        .count()
+        + 1
+}
+
+struct FunctionFinder<'a> {
+    source: &'a str,
+    functions: Vec<FunctionDef>,
+}
+
+impl FunctionFinder<'_> {
+    fn text(&self, span: Span) -> &str {
+        &self.source[span.start..span.end]
+    }
+
+    fn push(&mut self, name: String, code: String, span: Span, is_local: bool) {
+        self.functions.push(FunctionDef {
+            name,
+            code,
+            span,
+            start_line: line_at(self.source, span.start),
+            end_line: line_at(self.source, span.end),
+            is_local,
+        });
+    }
+
+    fn block(&mut self, block: &Block) {
+        for stat in block.stats.iter() {
+            match &stat.kind {
+                StatKind::Function { name, .. } => {
+                    let code = self.text(stat.span).to_string();
+                    self.push(name.qualified(), code, stat.span, false);
+                }
+                StatKind::LocalFunction { name, .. } => {
+                    let code = self.text(stat.span).to_string();
+                    self.push(name.clone(), code, stat.span, true);
+                }
+                StatKind::Local { names, exprs } => {
+                    let single = names.len() == 1 && exprs.len() == 1;
+                    for (name, expr) in names.iter().zip(exprs.iter()) {
+                        let span = if single { stat.span } else { expr.span };
+                        self.value(name.clone(), expr, span, true);
+                    }
+                }
+                StatKind::Assign { targets, exprs } => {
+                    let single = targets.len() == 1 && exprs.len() == 1;
+                    for (target, expr) in targets.iter().zip(exprs.iter()) {
+                        if let Some(name) = target.qualified_name() {
+                            let span = if single { stat.span } else { expr.span };
+                            self.value(name, expr, span, false);
+                        }
+                    }
+                }
+                StatKind::Do(block)
+                | StatKind::While { block, .. }
+                | StatKind::Repeat { block, .. }
+                | StatKind::NumericFor { block, .. }
+                | StatKind::GenericFor { block, .. } => self.block(block),
+                StatKind::If {
+                    branches,
+                    else_block,
+                } => {
+                    for (_, block) in branches.iter() {
+                        self.block(block);
+                    }
+                    if let Some(block) = else_block {
+                        self.block(block);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Records `expr` as `name` if it is a function, or descends into it if it is a table
+    fn value(&mut self, name: String, expr: &Expr, span: Span, is_local: bool) {
+        match &expr.kind {
+            ExprKind::Function(_) => {
+                let code = if span == expr.span {
+                    // only the local itself is declared, its fields are set through it
+                    let local = if is_local && !name.contains('.') {
+                        "local "
+                    } else {
+                        ""
+                    };
+                    format!("{}{} = {}", local, name, self.text(expr.span))
+                } else {
+                    self.text(span).to_string()
+                };
+                self.push(name, code, span, is_local);
+            }
+            ExprKind::Table(fields) => {
+                for field in fields.iter() {
+                    let (key, value) = match field {
+                        TableField::Named { key, value } => (key.clone(), value),
+                        TableField::Indexed { key, value } => match string_literal_value(key) {
+                            Some(key) => (key, value),
+                            None => continue,
+                        },
+                        TableField::Positional(_) => continue,
+                    };
+                    // the fields of a file-local table are only reachable through that local
+                    self.value(format!("{}.{}", name, key), value, value.span, is_local);
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    pub fn to(&self, other: Span) -> Span {
+        Span::new(self.start, other.end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Name(String),
+    Keyword(&'static str),
+    Number,
+    String,
+    LongString,
+    Symbol(&'static str),
+    Comment,
+    Eof,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    pub line: usize,
+}
+
+impl Token {
+    pub fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.kind, TokenKind::Symbol(s) if s == symbol)
+    }
+
+    pub fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.kind, TokenKind::Keyword(k) if k == keyword)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LuaSyntaxError {
+    pub message: String,
+    pub line: usize,
+}
+
+impl fmt::Display for LuaSyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for LuaSyntaxError {}
+
+const KEYWORDS: [&str; 22] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+// longest symbols first so that `...` wins over `..` and `.`
+const SYMBOLS: [&str; 27] = [
+    "...", "..", "==", "~=", "<=", ">=", "::", "+", "-", "*", "/", "%", "^", "#", "<", ">", "=",
+    "(", ")", "{", "}", "[", "]", ";", ":", ",", ".",
+];
+
+fn keyword(name: &str) -> Option<&'static str> {
+    KEYWORDS.iter().find(|keyword| **keyword == name).copied()
+}
+
+// Tokenizes Lua 5.1 source (plus LuaJIT's goto and labels, as Balatro runs on LuaJIT). Comments are kept as tokens so
+// that tools working on the token stream can decide what to do with them.
+pub fn tokenize(source: &str) -> Result<Vec<Token>, LuaSyntaxError> {
+    Lexer {
+        source,
+        bytes: source.as_bytes(),
+        pos: 0,
+        line: 1,
+    }
+    .run()
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    bytes: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl Lexer<'_> {
+    fn peek(&self, offset: usize) -> u8 {
+        *self.bytes.get(self.pos + offset).unwrap_or(&0)
+    }
+
+    fn error(&self, message: &str) -> LuaSyntaxError {
+        LuaSyntaxError {
+            message: message.to_string(),
+            line: self.line,
+        }
+    }
+
+    fn advance(&mut self) {
+        if self.peek(0) == b'\n' {
+            self.line += 1;
+        }
+        self.pos += 1;
+    }
+
+    fn run(mut self) -> Result<Vec<Token>, LuaSyntaxError> {
+        let mut tokens = Vec::new();
+        if self.source.starts_with('#') {
+            // shebang line
+            while self.pos < self.bytes.len() && self.peek(0) != b'\n' {
+                self.advance();
+            }
+        }
+        loop {
+            while self.pos < self.bytes.len() && self.peek(0).is_ascii_whitespace() {
+                self.advance();
+            }
+            let start = self.pos;
+            let line = self.line;
+            if self.pos >= self.bytes.len() {
+                tokens.push(Token {
+                    kind: TokenKind::Eof,
+                    span: Span::new(start, start),
+                    line,
+                });
+                return Ok(tokens);
+            }
+            let kind = self.next_kind()?;
+            tokens.push(Token {
+                kind,
+                span: Span::new(start, self.pos),
+                line,
+            });
+        }
+    }
+
+    fn next_kind(&mut self) -> Result<TokenKind, LuaSyntaxError> {
+        let ch = self.peek(0);
+        if ch == b'-' && self.peek(1) == b'-' {
+            self.pos += 2;
+            if let Some(level) = self.long_bracket_level() {
+                self.long_bracket(level, "unfinished long comment")?;
+            } else {
+                while self.pos < self.bytes.len() && self.peek(0) != b'\n' {
+                    self.advance();
+                }
+            }
+            return Ok(TokenKind::Comment);
+        }
+        if ch.is_ascii_alphabetic() || ch == b'_' {
+            let start = self.pos;
+            while self.peek(0).is_ascii_alphanumeric() || self.peek(0) == b'_' {
+                self.advance();
+            }
+            let name = &self.source[start..self.pos];
+            return Ok(match keyword(name) {
+                Some(keyword) => TokenKind::Keyword(keyword),
+                None => TokenKind::Name(name.to_string()),
+            });
+        }
+        if ch.is_ascii_digit() || (ch == b'.' && self.peek(1).is_ascii_digit()) {
+            self.number();
+            return Ok(TokenKind::Number);
+        }
+        if ch == b'"' || ch == b'\'' {
+            self.quoted_string(ch)?;
+            return Ok(TokenKind::String);
+        }
+        if ch == b'[' {
+            if let Some(level) = self.long_bracket_level() {
+                self.long_bracket(level, "unfinished long string")?;
+                return Ok(TokenKind::LongString);
+            }
+        }
+        for symbol in SYMBOLS {
+            if self.bytes[self.pos..].starts_with(symbol.as_bytes()) {
+                self.pos += symbol.len();
+                return Ok(TokenKind::Symbol(symbol));
+            }
+        }
+        let unexpected = self.source[self.pos..].chars().next().unwrap();
+        Err(self.error(&format!("unexpected symbol near '{}'", unexpected)))
+    }
+
+    fn number(&mut self) {
+        if self.peek(0) == b'0' && (self.peek(1) == b'x' || self.peek(1) == b'X') {
+            self.pos += 2;
+            while self.peek(0).is_ascii_hexdigit() || self.peek(0) == b'.' {
+                self.advance();
+            }
+            if self.peek(0) == b'p' || self.peek(0) == b'P' {
+                self.advance();
+                if self.peek(0) == b'+' || self.peek(0) == b'-' {
+                    self.advance();
+                }
+            }
+        } else {
+            while self.peek(0).is_ascii_digit() || self.peek(0) == b'.' {
+                self.advance();
+            }
+            if self.peek(0) == b'e' || self.peek(0) == b'E' {
+                self.advance();
+                if self.peek(0) == b'+' || self.peek(0) == b'-' {
+                    self.advance();
+                }
+            }
+        }
+        // like Lua, swallow trailing alphanumerics (LuaJIT suffixes such as 1ULL)
+        while self.peek(0).is_ascii_alphanumeric() || self.peek(0) == b'_' {
+            self.advance();
+        }
+    }
+
+    fn quoted_string(&mut self, quote: u8) -> Result<(), LuaSyntaxError> {
+        self.advance();
+        loop {
+            match self.peek(0) {
+                0 if self.pos >= self.bytes.len() => return Err(self.error("unfinished string")),
+                b'\n' => return Err(self.error("unfinished string")),
+                b'\\' => {
+                    self.advance();
+                    if self.pos >= self.bytes.len() {
+                        return Err(self.error("unfinished string"));
+                    }
+                    self.advance();
+                }
+                c if c == quote => {
+                    self.advance();
+                    return Ok(());
+                }
+                _ => self.advance(),
+            }
+        }
+    }
+
+    // Returns the level of a long bracket `[==[` starting at the current position
+    fn long_bracket_level(&self) -> Option<usize> {
+        if self.peek(0) != b'[' {
+            return None;
+        }
+        let mut level = 0;
+        while self.peek(1 + level) == b'=' {
+            level += 1;
+        }
+        if self.peek(1 + level) == b'[' {
+            Some(level)
+        } else {
+            None
+        }
+    }
+
+    fn long_bracket(&mut self, level: usize, unfinished: &str) -> Result<(), LuaSyntaxError> {
+        let close = format!("]{}]", "=".repeat(level));
+        self.pos += level + 2;
+        while self.pos < self.bytes.len() {
+            if self.bytes[self.pos..].starts_with(close.as_bytes()) {
+                self.pos += close.len();
+                return Ok(());
+            }
+            self.advance();
+        }
+        Err(self.error(unfinished))
+    }
+}
@@ -0,0 +1,595 @@
+use crate::lua::ast::{Block, Expr, ExprKind, FuncName, FunctionBody, Stat, StatKind, TableField};
+use crate::lua::lexer::{tokenize, LuaSyntaxError, Span, Token, TokenKind};
+
+const UNARY_PRIORITY: u8 = 8;
+
+// (left, right) priorities from the Lua 5.1 reference parser
+fn binary_priority(token: &Token) -> Option<(&'static str, u8, u8)> {
+    let op = match &token.kind {
+        TokenKind::Symbol(symbol) => *symbol,
+        TokenKind::Keyword(keyword) if *keyword == "and" || *keyword == "or" => *keyword,
+        _ => return None,
+    };
+    let (left, right) = match op {
+        "+" | "-" => (6, 6),
+        "*" | "/" | "%" => (7, 7),
+        "^" => (10, 9),
+        ".." => (5, 4),
+        "==" | "~=" | "<" | "<=" | ">" | ">=" => (3, 3),
+        "and" => (2, 2),
+        "or" => (1, 1),
+        _ => return None,
+    };
+    Some((op, left, right))
+}
+
+pub fn parse(source: &str) -> Result<Block, LuaSyntaxError> {
+    let tokens = tokenize(source)?
+        .into_iter()
+        .filter(|token| token.kind != TokenKind::Comment)
+        .collect();
+    let mut parser = Parser {
+        source,
+        tokens,
+        pos: 0,
+        last_end: 0,
+    };
+    let block = parser.block()?;
+    if !parser.check_eof() {
+        return Err(parser.error_near("'<eof>' expected"));
+    }
+    Ok(block)
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+    last_end: usize,
+}
+
+impl Parser<'_> {
+    fn current(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn peek(&self, offset: usize) -> &Token {
+        let index = (self.pos + offset).min(self.tokens.len() - 1);
+        &self.tokens[index]
+    }
+
+    fn check_eof(&self) -> bool {
+        self.current().kind == TokenKind::Eof
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        self.last_end = token.span.end;
+        token
+    }
+
+    fn error_near(&self, message: &str) -> LuaSyntaxError {
+        let token = self.current();
+        let near = match token.kind {
+            TokenKind::Eof => "<eof>".to_string(),
+            _ => self.source[token.span.start..token.span.end].to_string(),
+        };
+        LuaSyntaxError {
+            message: format!("{} near '{}'", message, near),
+            line: token.line,
+        }
+    }
+
+    fn check_symbol(&self, symbol: &str) -> bool {
+        self.current().is_symbol(symbol)
+    }
+
+    fn check_keyword(&self, keyword: &str) -> bool {
+        self.current().is_keyword(keyword)
+    }
+
+    fn accept_symbol(&mut self, symbol: &str) -> bool {
+        if self.check_symbol(symbol) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<Token, LuaSyntaxError> {
+        if self.check_symbol(symbol) {
+            Ok(self.next())
+        } else {
+            Err(self.error_near(&format!("'{}' expected", symbol)))
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<Token, LuaSyntaxError> {
+        if self.check_keyword(keyword) {
+            Ok(self.next())
+        } else {
+            Err(self.error_near(&format!("'{}' expected", keyword)))
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<String, LuaSyntaxError> {
+        match &self.current().kind {
+            TokenKind::Name(name) => {
+                let name = name.clone();
+                self.next();
+                Ok(name)
+            }
+            _ => Err(self.error_near("<name> expected")),
+        }
+    }
+
+    fn block_follows(&self) -> bool {
+        self.check_eof()
+            || ["else", "elseif", "end", "until"]
+                .iter()
+                .any(|keyword| self.check_keyword(keyword))
+    }
+
+    fn block(&mut self) -> Result<Block, LuaSyntaxError> {
+        let start = self.current().span.start;
+        let mut stats = Vec::new();
+        while !self.block_follows() {
+            if self.accept_symbol(";") {
+                continue;
+            }
+            let is_return = self.check_keyword("return");
+            stats.push(self.statement()?);
+            self.accept_symbol(";");
+            if is_return {
+                break;
+            }
+        }
+        let end = self.last_end.max(start);
+        Ok(Block {
+            stats,
+            span: Span::new(start, end),
+        })
+    }
+
+    fn statement(&mut self) -> Result<Stat, LuaSyntaxError> {
+        let start = self.current().span.start;
+        let kind = match &self.current().kind {
+            TokenKind::Keyword("if") => self.if_statement()?,
+            TokenKind::Keyword("while") => {
+                self.next();
+                let cond = self.expr()?;
+                self.expect_keyword("do")?;
+                let block = self.block()?;
+                self.expect_keyword("end")?;
+                StatKind::While { cond, block }
+            }
+            TokenKind::Keyword("do") => {
+                self.next();
+                let block = self.block()?;
+                self.expect_keyword("end")?;
+                StatKind::Do(block)
+            }
+            TokenKind::Keyword("for") => self.for_statement()?,
+            TokenKind::Keyword("repeat") => {
+                self.next();
+                let block = self.block()?;
+                self.expect_keyword("until")?;
+                let cond = self.expr()?;
+                StatKind::Repeat { block, cond }
+            }
+            TokenKind::Keyword("function") => {
+                self.next();
+                let mut path = vec![self.expect_name()?];
+                while self.accept_symbol(".") {
+                    path.push(self.expect_name()?);
+                }
+                let method = if self.accept_symbol(":") {
+                    Some(self.expect_name()?)
+                } else {
+                    None
+                };
+                let func = Box::new(self.function_body(start, method.is_some())?);
+                StatKind::Function {
+                    name: FuncName { path, method },
+                    func,
+                }
+            }
+            TokenKind::Keyword("local") => {
+                self.next();
+                if self.check_keyword("function") {
+                    self.next();
+                    let name = self.expect_name()?;
+                    let func = Box::new(self.function_body(start, false)?);
+                    StatKind::LocalFunction { name, func }
+                } else {
+                    let mut names = vec![self.expect_name()?];
+                    while self.accept_symbol(",") {
+                        names.push(self.expect_name()?);
+                    }
+                    let exprs = if self.accept_symbol("=") {
+                        self.expr_list()?
+                    } else {
+                        Vec::new()
+                    };
+                    StatKind::Local { names, exprs }
+                }
+            }
+            TokenKind::Keyword("return") => {
+                self.next();
+                let exprs = if self.block_follows() || self.check_symbol(";") {
+                    Vec::new()
+                } else {
+                    self.expr_list()?
+                };
+                StatKind::Return(exprs)
+            }
+            TokenKind::Keyword("break") => {
+                self.next();
+                StatKind::Break
+            }
+            TokenKind::Keyword("goto") => {
+                self.next();
+                StatKind::Goto(self.expect_name()?)
+            }
+            TokenKind::Symbol("::") => {
+                self.next();
+                let name = self.expect_name()?;
+                self.expect_symbol("::")?;
+                StatKind::Label(name)
+            }
+            _ => self.expr_statement()?,
+        };
+        Ok(Stat {
+            kind,
+            span: Span::new(start, self.last_end),
+        })
+    }
+
+    fn if_statement(&mut self) -> Result<StatKind, LuaSyntaxError> {
+        self.next();
+        let mut branches = Vec::new();
+        let cond = self.expr()?;
+        self.expect_keyword("then")?;
+        branches.push((cond, self.block()?));
+        let mut else_block = None;
+        loop {
+            if self.check_keyword("elseif") {
+                self.next();
+                let cond = self.expr()?;
+                self.expect_keyword("then")?;
+                branches.push((cond, self.block()?));
+            } else if self.check_keyword("else") {
+                self.next();
+                else_block = Some(self.block()?);
+                self.expect_keyword("end")?;
+                break;
+            } else {
+                self.expect_keyword("end")?;
+                break;
+            }
+        }
+        Ok(StatKind::If {
+            branches,
+            else_block,
+        })
+    }
+
+    fn for_statement(&mut self) -> Result<StatKind, LuaSyntaxError> {
+        self.next();
+        let first = self.expect_name()?;
+        if self.accept_symbol("=") {
+            let start = Box::new(self.expr()?);
+            self.expect_symbol(",")?;
+            let end = Box::new(self.expr()?);
+            let step = if self.accept_symbol(",") {
+                Some(Box::new(self.expr()?))
+            } else {
+                None
+            };
+            self.expect_keyword("do")?;
+            let block = self.block()?;
+            self.expect_keyword("end")?;
+            return Ok(StatKind::NumericFor {
+                var: first,
+                start,
+                end,
+                step,
+                block,
+            });
+        }
+        let mut names = vec![first];
+        while self.accept_symbol(",") {
+            names.push(self.expect_name()?);
+        }
+        self.expect_keyword("in")?;
+        let exprs = self.expr_list()?;
+        self.expect_keyword("do")?;
+        let block = self.block()?;
+        self.expect_keyword("end")?;
+        Ok(StatKind::GenericFor {
+            names,
+            exprs,
+            block,
+        })
+    }
+
+    fn expr_statement(&mut self) -> Result<StatKind, LuaSyntaxError> {
+        let expr = self.suffixed_expr()?;
+        if self.check_symbol("=") || self.check_symbol(",") {
+            let mut targets = vec![expr];
+            while self.accept_symbol(",") {
+                targets.push(self.suffixed_expr()?);
+            }
+            for target in targets.iter() {
+                if !matches!(
+                    target.kind,
+                    ExprKind::Name(_) | ExprKind::Field { .. } | ExprKind::Index { .. }
+                ) {
+                    return Err(self.error_near("syntax error"));
+                }
+            }
+            self.expect_symbol("=")?;
+            let exprs = self.expr_list()?;
+            return Ok(StatKind::Assign { targets, exprs });
+        }
+        match expr.kind {
+            ExprKind::Call { .. } | ExprKind::MethodCall { .. } => Ok(StatKind::Call(expr)),
+            _ => Err(self.error_near("syntax error")),
+        }
+    }
+
+    fn function_body(
+        &mut self,
+        start: usize,
+        method: bool,
+    ) -> Result<FunctionBody, LuaSyntaxError> {
+        self.expect_symbol("(")?;
+        let mut params = Vec::new();
+        if method {
+            params.push("self".to_string());
+        }
+        let mut vararg = false;
+        if !self.check_symbol(")") {
+            loop {
+                if self.accept_symbol("...") {
+                    vararg = true;
+                    break;
+                }
+                params.push(self.expect_name()?);
+                if !self.accept_symbol(",") {
+                    break;
+                }
+            }
+        }
+        self.expect_symbol(")")?;
+        let block = self.block()?;
+        self.expect_keyword("end")?;
+        Ok(FunctionBody {
+            params,
+            vararg,
+            block,
+            span: Span::new(start, self.last_end),
+        })
+    }
+
+    fn expr_list(&mut self) -> Result<Vec<Expr>, LuaSyntaxError> {
+        let mut exprs = vec![self.expr()?];
+        while self.accept_symbol(",") {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn expr(&mut self) -> Result<Expr, LuaSyntaxError> {
+        self.sub_expr(0)
+    }
+
+    fn sub_expr(&mut self, limit: u8) -> Result<Expr, LuaSyntaxError> {
+        let start = self.current().span.start;
+        let unary = match &self.current().kind {
+            TokenKind::Keyword("not") => Some("not"),
+            TokenKind::Symbol("-") => Some("-"),
+            TokenKind::Symbol("#") => Some("#"),
+            _ => None,
+        };
+        let mut lhs = match unary {
+            Some(op) => {
+                self.next();
+                let expr = self.sub_expr(UNARY_PRIORITY)?;
+                Expr {
+                    span: Span::new(start, expr.span.end),
+                    kind: ExprKind::UnOp {
+                        op,
+                        expr: Box::new(expr),
+                    },
+                }
+            }
+            None => self.simple_expr()?,
+        };
+        while let Some((op, left, right)) = binary_priority(self.current()) {
+            if left <= limit {
+                break;
+            }
+            self.next();
+            let rhs = self.sub_expr(right)?;
+            lhs = Expr {
+                span: lhs.span.to(rhs.span),
+                kind: ExprKind::BinOp {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn simple_expr(&mut self) -> Result<Expr, LuaSyntaxError> {
+        let token = self.current().clone();
+        let text = || self.source[token.span.start..token.span.end].to_string();
+        let kind = match &token.kind {
+            TokenKind::Number => ExprKind::Number(text()),
+            TokenKind::String | TokenKind::LongString => ExprKind::String(text()),
+            TokenKind::Keyword("nil") => ExprKind::Nil,
+            TokenKind::Keyword("true") => ExprKind::True,
+            TokenKind::Keyword("false") => ExprKind::False,
+            TokenKind::Symbol("...") => ExprKind::Vararg,
+            TokenKind::Symbol("{") => return self.table(),
+            TokenKind::Keyword("function") => {
+                self.next();
+                let func = self.function_body(token.span.start, false)?;
+                return Ok(Expr {
+                    span: func.span,
+                    kind: ExprKind::Function(func),
+                });
+            }
+            _ => return self.suffixed_expr(),
+        };
+        self.next();
+        Ok(Expr {
+            kind,
+            span: token.span,
+        })
+    }
+
+    fn primary_expr(&mut self) -> Result<Expr, LuaSyntaxError> {
+        let token = self.current().clone();
+        match &token.kind {
+            TokenKind::Name(name) => {
+                self.next();
+                Ok(Expr {
+                    kind: ExprKind::Name(name.clone()),
+                    span: token.span,
+                })
+            }
+            TokenKind::Symbol("(") => {
+                self.next();
+                let expr = self.expr()?;
+                let close = self.expect_symbol(")")?;
+                Ok(Expr {
+                    kind: ExprKind::Paren(Box::new(expr)),
+                    span: token.span.to(close.span),
+                })
+            }
+            _ => Err(self.error_near("unexpected symbol")),
+        }
+    }
+
+    fn suffixed_expr(&mut self) -> Result<Expr, LuaSyntaxError> {
+        let mut expr = self.primary_expr()?;
+        loop {
+            let start = expr.span.start;
+            match &self.current().kind {
+                TokenKind::Symbol(".") => {
+                    self.next();
+                    let name = self.expect_name()?;
+                    expr = Expr {
+                        kind: ExprKind::Field {
+                            obj: Box::new(expr),
+                            name,
+                        },
+                        span: Span::new(start, self.last_end),
+                    };
+                }
+                TokenKind::Symbol("[") => {
+                    self.next();
+                    let key = self.expr()?;
+                    self.expect_symbol("]")?;
+                    expr = Expr {
+                        kind: ExprKind::Index {
+                            obj: Box::new(expr),
+                            key: Box::new(key),
+                        },
+                        span: Span::new(start, self.last_end),
+                    };
+                }
+                TokenKind::Symbol(":") => {
+                    self.next();
+                    let method = self.expect_name()?;
+                    let args = self.call_args()?;
+                    expr = Expr {
+                        kind: ExprKind::MethodCall {
+                            obj: Box::new(expr),
+                            method,
+                            args,
+                        },
+                        span: Span::new(start, self.last_end),
+                    };
+                }
+                TokenKind::Symbol("(")
+                | TokenKind::Symbol("{")
+                | TokenKind::String
+                | TokenKind::LongString => {
+                    let args = self.call_args()?;
+                    expr = Expr {
+                        kind: ExprKind::Call {
+                            func: Box::new(expr),
+                            args,
+                        },
+                        span: Span::new(start, self.last_end),
+                    };
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn call_args(&mut self) -> Result<Vec<Expr>, LuaSyntaxError> {
+        match &self.current().kind {
+            TokenKind::String | TokenKind::LongString => Ok(vec![self.simple_expr()?]),
+            TokenKind::Symbol("{") => Ok(vec![self.table()?]),
+            TokenKind::Symbol("(") => {
+                self.next();
+                let args = if self.check_symbol(")") {
+                    Vec::new()
+                } else {
+                    self.expr_list()?
+                };
+                self.expect_symbol(")")?;
+                Ok(args)
+            }
+            _ => Err(self.error_near("function arguments expected")),
+        }
+    }
+
+    fn table(&mut self) -> Result<Expr, LuaSyntaxError> {
+        let open = self.expect_symbol("{")?;
+        let mut fields = Vec::new();
+        while !self.check_symbol("}") {
+            let field = if self.check_symbol("[") {
+                self.next();
+                let key = self.expr()?;
+                self.expect_symbol("]")?;
+                self.expect_symbol("=")?;
+                TableField::Indexed {
+                    key,
+                    value: self.expr()?,
+                }
+            } else if matches!(self.current().kind, TokenKind::Name(_))
+                && self.peek(1).is_symbol("=")
+            {
+                let key = self.expect_name()?;
+                self.next();
+                TableField::Named {
+                    key,
+                    value: self.expr()?,
+                }
+            } else {
+                TableField::Positional(self.expr()?)
+            };
+            fields.push(field);
+            if !self.accept_symbol(",") && !self.accept_symbol(";") {
+                break;
+            }
+        }
+        let close = self.expect_symbol("}")?;
+        Ok(Expr {
+            kind: ExprKind::Table(fields),
+            span: open.span.to(close.span),
+        })
+    }
+}
@@ -0,0 +1,506 @@
+use crate::lua::ast::{Block, Expr, ExprKind, FunctionBody, Stat, StatKind};
+use crate::lua::lexer::Span;
+use crate::lua::parser::parse;
+use mlua::prelude::{LuaError, LuaResult, LuaValue};
+use mlua::{FromLua, Lua};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Anchor {
+    // first statement of the function body
+    Start,
+    // after the last statement, or before it when it is a return
+    End,
+    // every return statement of the function (not of nested functions)
+    Return,
+    // every statement calling the named function, searched in nested functions too
+    Call(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Position {
+    Before,
+    After,
+    // replaces the matched node itself, e.g. the call expression
+    Replace,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AstTarget {
+    pub anchor: Anchor,
+    pub position: Position,
+    // 1-based index of the match to patch, every match when None
+    pub nth: Option<usize>,
+}
+
+impl AstTarget {
+    // e.g. `before call to ease_dollars (match 2)`
+    pub fn describe(&self) -> String {
+        let position = match self.position {
+            Position::Before => "before",
+            Position::After => "after",
+            Position::Replace => "replace",
+        };
+        match self.nth {
+            Some(nth) => format!("{} {} (match {})", position, describe(&self.anchor), nth),
+            None => format!("{} {}", position, describe(&self.anchor)),
+        }
+    }
+}
+
+// A replacement of `span` in the original source by `text`, empty spans are insertions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub span: Span,
+    pub text: String,
+}
+
+// Applies non-overlapping edits sorted by position
+pub fn apply_edits(code: &str, edits: &[Edit]) -> String {
+    let mut patched = code.to_string();
+    // from the back so earlier offsets stay valid
+    for edit in edits.iter().rev() {
+        patched.replace_range(edit.span.start..edit.span.end, &edit.text);
+    }
+    patched
+}
+
+// Ranges of the patched source holding the text of each edit
+pub fn edited_regions(edits: &[Edit]) -> Vec<Span> {
+    let mut regions = Vec::new();
+    let mut offset: isize = 0;
+    for edit in edits.iter() {
+        let start = (edit.span.start as isize + offset) as usize;
+        regions.push(Span::new(start, start + edit.text.len()));
+        offset += edit.text.len() as isize - (edit.span.end - edit.span.start) as isize;
+    }
+    regions
+}
+
+// `span` of `code` with up to `context` bytes around it, the span itself is marked with >>> <<<
+pub fn excerpt(code: &str, span: Span, context: usize) -> String {
+    let floor = |mut at: usize| {
+        while !code.is_char_boundary(at) {
+            at -= 1;
+        }
+        at
+    };
+    let start = floor(span.start.saturating_sub(context));
+    let end = floor((span.end + context).min(code.len()));
+    format!(
+        "{}{}>>>{}<<<{}{}",
+        if start > 0 { "..." } else { "" },
+        &code[start..span.start],
+        &code[span.start..span.end],
+        &code[span.end..end],
+        if end < code.len() { "..." } else { "" }
+    )
+}
+
+// Edits inserting `code` into the function defined by `function_code` at the sites described
+// by `target`, one per site.
+pub fn function_edits(
+    function_code: &str,
+    target: &AstTarget,
+    code: &str,
+) -> Result<Vec<Edit>, String> {
+    let chunk = parse(function_code).map_err(|e| format!("Error parsing function: {}", e))?;
+    let func = chunk
+        .stats
+        .first()
+        .and_then(defined_function)
+        .ok_or_else(|| "Code is not a function definition".to_string())?;
+
+    // (span of the node, span of the enclosing statement)
+    let mut sites: Vec<(Span, Span)> = Vec::new();
+    match &target.anchor {
+        Anchor::Start => {
+            let span = match func.block.stats.first() {
+                Some(stat) => stat.span,
+                None => body_end(func),
+            };
+            sites.push((span, span));
+        }
+        Anchor::End => {
+            let span = match func.block.stats.last() {
+                Some(stat) if matches!(stat.kind, StatKind::Return(_)) => stat.span,
+                Some(stat) if target.position == Position::After => stat.span,
+                _ => body_end(func),
+            };
+            sites.push((span, span));
+        }
+        Anchor::Return => collect_returns(&func.block, &mut sites),
+        Anchor::Call(name) => collect_calls(&func.block, name, &mut sites),
+    }
+
+    sites.sort_by_key(|(node, _)| node.start);
+    if sites.is_empty() {
+        return Err(format!("No match for {}", describe(&target.anchor)));
+    }
+    if let Some(nth) = target.nth {
+        if nth == 0 || nth > sites.len() {
+            return Err(format!(
+                "Match {} requested for {} but only {} found",
+                nth,
+                describe(&target.anchor),
+                sites.len()
+            ));
+        }
+        sites = vec![sites[nth - 1]];
+    }
+
+    // keeps inserted statements from merging with neighbouring tokens
+    let padded = |at: usize| {
+        let before = function_code[..at].chars().next_back();
+        let after = function_code[at..].chars().next();
+        let space = |c: Option<char>| match c {
+            Some(c) if !c.is_whitespace() => " ",
+            _ => "",
+        };
+        format!("{}{}{}", space(before), code, space(after))
+    };
+    let mut edits: Vec<Edit> = Vec::new();
+    for (node, stat) in sites.iter() {
+        let (span, text) = match target.position {
+            Position::Before => (Span::new(stat.start, stat.start), padded(stat.start)),
+            Position::After => {
+                if is_return_at(&func.block, *stat) {
+                    return Err("Cannot insert code after a return statement".to_string());
+                }
+                (Span::new(stat.end, stat.end), padded(stat.end))
+            }
+            Position::Replace => (*node, code.to_string()),
+        };
+        edits.push(Edit { span, text });
+    }
+    edits.sort_by_key(|edit| (edit.span.start, std::cmp::Reverse(edit.span.end)));
+    edits.dedup_by(|a, b| a.span == b.span);
+    // a call nested in a replaced call, e.g. the inner f of f(f(x)), is replaced along with it
+    let mut kept: Vec<Edit> = Vec::new();
+    for edit in edits {
+        match kept.last() {
+            Some(last) if edit.span.start < last.span.end => {}
+            _ => kept.push(edit),
+        }
+    }
+    Ok(kept)
+}
+
+fn describe(anchor: &Anchor) -> String {
+    match anchor {
+        Anchor::Start => "function start".to_string(),
+        Anchor::End => "function end".to_string(),
+        Anchor::Return => "return statement".to_string(),
+        Anchor::Call(name) => format!("call to {}", name),
+    }
+}
+
+// The function defined by a statement, either with `function name()` or by assignment
+fn defined_function(stat: &Stat) -> Option<&FunctionBody> {
+    if let Some(func) = stat.function_body() {
+        return Some(func);
+    }
+    match &stat.kind {
+        StatKind::Local { exprs, .. } | StatKind::Assign { exprs, .. } => match exprs.first() {
+            Some(Expr {
+                kind: ExprKind::Function(func),
+                ..
+            }) => Some(func),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Empty span right before the closing `end` of a function
+fn body_end(func: &FunctionBody) -> Span {
+    let end = func.span.end - "end".len();
+    Span::new(end, end)
+}
+
+fn is_return_at(block: &Block, span: Span) -> bool {
+    block.stats.iter().any(|stat| {
+        (stat.span == span && matches!(stat.kind, StatKind::Return(_)))
+            || stat.blocks().iter().any(|block| is_return_at(block, span))
+            || stat.exprs().iter().any(|expr| {
+                functions_in(expr)
+                    .iter()
+                    .any(|func| is_return_at(&func.block, span))
+            })
+            || stat
+                .function_body()
+                .is_some_and(|func| is_return_at(&func.block, span))
+    })
+}
+
+fn collect_returns(block: &Block, sites: &mut Vec<(Span, Span)>) {
+    for stat in block.stats.iter() {
+        if let StatKind::Return(_) = stat.kind {
+            sites.push((stat.span, stat.span));
+        }
+        for block in stat.blocks() {
+            collect_returns(block, sites);
+        }
+    }
+}
+
+fn call_matches(expr: &Expr, name: &str) -> bool {
+    let last = match &expr.kind {
+        ExprKind::Call { func, .. } => match &func.kind {
+            ExprKind::Name(name) => Some(name.clone()),
+            ExprKind::Field { name, .. } => Some(name.clone()),
+            _ => None,
+        },
+        ExprKind::MethodCall { method, .. } => Some(method.clone()),
+        _ => None,
+    };
+    if name.contains('.') || name.contains(':') {
+        expr.callee_name().as_deref() == Some(name)
+    } else {
+        last.as_deref() == Some(name)
+    }
+}
+
+// Function expressions reachable from `expr` without entering another function
+fn functions_in(expr: &Expr) -> Vec<&FunctionBody> {
+    match &expr.kind {
+        ExprKind::Function(func) => vec![func],
+        _ => expr.children().into_iter().flat_map(functions_in).collect(),
+    }
+}
+
+fn collect_calls(block: &Block, name: &str, sites: &mut Vec<(Span, Span)>) {
+    fn calls_in(expr: &Expr, name: &str, stat: Span, sites: &mut Vec<(Span, Span)>) {
+        if let ExprKind::Function(func) = &expr.kind {
+            collect_calls(&func.block, name, sites);
+            return;
+        }
+        if call_matches(expr, name) {
+            sites.push((expr.span, stat));
+        }
+        for child in expr.children() {
+            calls_in(child, name, stat, sites);
+        }
+    }
+
+    for stat in block.stats.iter() {
+        for expr in stat.exprs() {
+            calls_in(expr, name, stat.span, sites);
+        }
+        if let Some(func) = stat.function_body() {
+            collect_calls(&func.block, name, sites);
+        }
+        for block in stat.blocks() {
+            collect_calls(block, name, sites);
+        }
+    }
+}
+
+impl Anchor {
+    // `at` as used by injection target tables and patch files, `name` is the called function
+    pub fn parse(at: &str, name: Option<String>) -> Result<Anchor, String> {
+        match at {
+            "start" => Ok(Anchor::Start),
+            "end" => Ok(Anchor::End),
+            "return" => Ok(Anchor::Return),
+            "call" => name
+                .map(Anchor::Call)
+                .ok_or_else(|| "Missing name of the called function".to_string()),
+            at => Err(format!("Unknown injection anchor: {}", at)),
+        }
+    }
+}
+
+impl Position {
+    pub fn parse(position: Option<&str>) -> Result<Position, String> {
+        match position {
+            None | Some("before") => Ok(Position::Before),
+            Some("after") => Ok(Position::After),
+            Some("replace") => Ok(Position::Replace),
+            Some(position) => Err(format!("Unknown injection position: {}", position)),
+        }
+    }
+}
+
+impl FromLua<'_> for AstTarget {
+    fn from_lua(value: LuaValue, _: &'_ Lua) -> LuaResult<Self> {
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => {
+                return Err(LuaError::RuntimeError(
+                    "Expected an injection target table".to_string(),
+                ))
+            }
+        };
+        let anchor = Anchor::parse(&table.get::<_, String>("at")?, table.get("name")?)
+            .map_err(LuaError::RuntimeError)?;
+        let position = Position::parse(table.get::<_, Option<String>>("position")?.as_deref())
+            .map_err(LuaError::RuntimeError)?;
+        Ok(AstTarget {
+            anchor,
+            position,
+            nth: table.get("nth")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum TextMode {
+    #[default]
+    Replace,
+    Before,
+    After,
+}
+
+impl TextMode {
+    pub fn parse(mode: Option<&str>) -> Result<TextMode, String> {
+        match mode {
+            None | Some("replace") => Ok(TextMode::Replace),
+            Some("before") => Ok(TextMode::Before),
+            Some("after") => Ok(TextMode::After),
+            Some(mode) => Err(format!("Unknown injection mode: {}", mode)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TextTarget {
+    pub mode: TextMode,
+    // 1-based index of the occurrence to patch, every occurrence when None
+    pub occurrence: Option<usize>,
+    // treat the code to find as a regex, `$1` or `${name}` in the inserted code expand to captures
+    pub regex: bool,
+}
+
+// Edits patching every (or the chosen) occurrence of `find` in `code`, or an error naming the
+// closest near-match.
+pub fn text_edits(
+    code: &str,
+    find: &str,
+    insert: &str,
+    target: &TextTarget,
+) -> Result<Vec<Edit>, String> {
+    // (range of the match, text to insert for it)
+    let mut matches: Vec<(Span, String)> = if target.regex {
+        let re = regex::Regex::new(find).map_err(|e| format!("Invalid pattern: {}", e))?;
+        re.captures_iter(code)
+            .map(|captures| {
+                let whole = captures.get(0).unwrap();
+                let mut expanded = String::new();
+                captures.expand(insert, &mut expanded);
+                (Span::new(whole.start(), whole.end()), expanded)
+            })
+            .collect()
+    } else {
+        if find.is_empty() {
+            return Err("Code to find is empty".to_string());
+        }
+        code.match_indices(find)
+            .map(|(start, _)| (Span::new(start, start + find.len()), insert.to_string()))
+            .collect()
+    };
+
+    if matches.is_empty() {
+        if target.regex {
+            return Err(format!("Pattern not found: {}", find));
+        }
+        return Err(match closest_match(code, find) {
+            Some((near, similarity)) => format!(
+                "Code not found, closest match ({:.0}% similar): {}",
+                similarity * 100.0,
+                near
+            ),
+            None => "Code not found".to_string(),
+        });
+    }
+    if let Some(occurrence) = target.occurrence {
+        if occurrence == 0 || occurrence > matches.len() {
+            return Err(format!(
+                "Occurrence {} requested but only {} found",
+                occurrence,
+                matches.len()
+            ));
+        }
+        matches = vec![matches.swap_remove(occurrence - 1)];
+    }
+
+    Ok(matches
+        .into_iter()
+        .map(|(span, text)| {
+            let span = match target.mode {
+                TextMode::Replace => span,
+                TextMode::Before => Span::new(span.start, span.start),
+                TextMode::After => Span::new(span.end, span.end),
+            };
+            Edit { span, text }
+        })
+        .collect())
+}
+
+// Finds the window of `code` most similar to `find`, starting at word boundaries
+pub fn closest_match(code: &str, find: &str) -> Option<(String, f64)> {
+    fn distance(a: &[char], b: &[char]) -> usize {
+        let mut previous: Vec<usize> = (0..=b.len()).collect();
+        let mut current = vec![0; b.len() + 1];
+        for (i, ca) in a.iter().enumerate() {
+            current[0] = i + 1;
+            for (j, cb) in b.iter().enumerate() {
+                let cost = if ca == cb { 0 } else { 1 };
+                current[j + 1] = (previous[j] + cost)
+                    .min(previous[j + 1] + 1)
+                    .min(current[j] + 1);
+            }
+            std::mem::swap(&mut previous, &mut current);
+        }
+        previous[b.len()]
+    }
+
+    let code: Vec<char> = code.chars().collect();
+    let find: Vec<char> = find.chars().collect();
+    if code.is_empty() || find.is_empty() {
+        return None;
+    }
+    let width = find.len().min(code.len());
+    let windows = code.len() - width + 1;
+    // bound the work for long functions and long search strings
+    let stride = (windows * width * width / 50_000_000).max(1);
+    let mut best: Option<(usize, usize)> = None;
+    for start in (0..windows).step_by(stride) {
+        if start > 0 && !code[start - 1].is_whitespace() && code[start].is_alphanumeric() {
+            continue;
+        }
+        let d = distance(&code[start..start + width], &find);
+        if best.is_none_or(|(_, best)| d < best) {
+            best = Some((start, d));
+        }
+    }
+    // the near-match may be a little shorter or longer than what was searched for
+    let (start, _) = best?;
+    let slack = (width / 4).max(1);
+    let (end, d) = (width.saturating_sub(slack).max(1)..=width + slack)
+        .map(|len| (start + len).min(code.len()))
+        .map(|end| (end, distance(&code[start..end], &find)))
+        .min_by_key(|(_, d)| *d)?;
+    let near: String = code[start..end].iter().collect();
+    Some((near, 1.0 - d as f64 / find.len().max(end - start) as f64))
+}
+
+impl FromLua<'_> for TextTarget {
+    fn from_lua(value: LuaValue, _: &'_ Lua) -> LuaResult<Self> {
+        let table = match value {
+            LuaValue::Nil => return Ok(TextTarget::default()),
+            LuaValue::Table(table) => table,
+            _ => {
+                return Err(LuaError::RuntimeError(
+                    "Expected an injection options table".to_string(),
+                ))
+            }
+        };
+        let mode = TextMode::parse(table.get::<_, Option<String>>("mode")?.as_deref())
+            .map_err(LuaError::RuntimeError)?;
+        Ok(TextTarget {
+            mode,
+            occurrence: table.get("occurrence")?,
+            regex: table.get::<_, Option<bool>>("regex")?.unwrap_or(false),
+        })
+    }
+}
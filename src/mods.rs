@@ -1,9 +1,12 @@
 use std::collections::{HashMap, HashSet};
 
 use crate::core::get_love_dir;
+use crate::persistence::write_atomic;
+use crate::sources::game_version;
 use crate::structs::localmod::LocalMod;
 use crate::structs::modinfo::ModInfo;
-use crate::utils::validate_schema;
+use crate::structs::modupdate::{ModUpdate, ModUpdateResult};
+use crate::utils::{is_newer_version, validate_schema};
 use crate::VERSION;
 use mlua::prelude::{LuaError, LuaResult, LuaTable};
 use mlua::{Lua, Table};
@@ -82,6 +85,7 @@ fn get_mods_from_repo(repo_url: String) -> Result<Vec<ModInfo>, reqwest::Error>
         let description = mod_info["description"].as_array().unwrap();
         let version = mod_info["version"].as_str().unwrap();
         let authors = mod_info["authors"].as_array().unwrap();
+        let changelog = mod_info["changelog"].as_str();
         mod_infos.push(ModInfo {
             url: url.to_string(),
             id: id.to_string(),
@@ -95,6 +99,7 @@ fn get_mods_from_repo(repo_url: String) -> Result<Vec<ModInfo>, reqwest::Error>
                 .iter()
                 .map(|a| a.as_str().unwrap().to_string())
                 .collect(),
+            changelog: changelog.map(|c| c.to_string()),
         });
     }
     Ok(mod_infos)
@@ -112,6 +117,7 @@ pub fn get_local_mods(lua: &Lua) -> LuaResult<Vec<LocalMod>> {
     let mut local_mods = Vec::new();
 
     let balamod_version = lua.load("require 'balamod_version'").eval::<String>()?;
+    let game_version = game_version(lua);
 
     for mod_dir in mod_dirs {
         let mod_dir: String = mod_dir?.path().display().to_string();
@@ -207,6 +213,15 @@ pub fn get_local_mods(lua: &Lua) -> LuaResult<Vec<LocalMod>> {
         }
 
         manifest.enabled = !std::path::Path::new(&format!("{}/disable.it", mod_dir)).exists();
+        manifest.pinned = std::path::Path::new(&format!("{}/pin.it", mod_dir)).exists();
+        manifest.game_compatible = game_version.matches(&manifest.game_versions);
+        if !manifest.game_compatible {
+            println!(
+                "Mod {} does not support game version {}",
+                manifest.id,
+                game_version.fingerprint()
+            );
+        }
 
         local_mods.push(manifest);
     }
@@ -214,6 +229,80 @@ pub fn get_local_mods(lua: &Lua) -> LuaResult<Vec<LocalMod>> {
     Ok(local_mods)
 }
 
+pub fn get_ignored_versions(lua: &Lua, id: &str) -> LuaResult<Vec<String>> {
+    let love_dir = get_love_dir(lua)?;
+    let ignore_file = format!("{}/mods/{}/ignore.it", love_dir, id);
+    if !std::path::Path::new(&ignore_file).exists() {
+        return Ok(Vec::new());
+    }
+    let content = std::fs::read_to_string(ignore_file)?;
+    Ok(content
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+pub fn ignore_mod_version(lua: &Lua, id: &str, version: &str) -> LuaResult<()> {
+    let mut ignored = get_ignored_versions(lua, id)?;
+    if ignored.iter().any(|ignored| ignored == version) {
+        return Ok(());
+    }
+    ignored.push(version.to_string());
+    let love_dir = get_love_dir(lua)?;
+    let ignore_file = format!("{}/mods/{}/ignore.it", love_dir, id);
+    write_atomic(ignore_file, ignored.join("\n"))?;
+    Ok(())
+}
+
+pub fn check_updates(lua: &Lua, mods: Vec<ModInfo>) -> LuaResult<Vec<ModUpdate>> {
+    let mut updates = Vec::new();
+    for local_mod in get_local_mods(lua)? {
+        if local_mod.pinned {
+            println!("Mod {} is pinned, skipping update check", local_mod.id);
+            continue;
+        }
+        let mod_info = match mods.iter().find(|mod_info| mod_info.id == local_mod.id) {
+            Some(mod_info) => mod_info,
+            None => continue,
+        };
+        if !is_newer_version(&local_mod.version, &mod_info.version) {
+            continue;
+        }
+        if get_ignored_versions(lua, &local_mod.id)?.contains(&mod_info.version) {
+            println!(
+                "Ignoring version {} of mod {}",
+                mod_info.version, local_mod.id
+            );
+            continue;
+        }
+        updates.push(ModUpdate {
+            id: local_mod.id,
+            name: local_mod.name,
+            current_version: local_mod.version,
+            new_version: mod_info.version.clone(),
+            changelog: mod_info.changelog.clone(),
+            mod_info: mod_info.clone(),
+        });
+    }
+    Ok(updates)
+}
+
+// Applies every available update, a failed one doesn't stop the others
+pub fn update_all(lua: &Lua, mods: Vec<ModInfo>) -> LuaResult<Vec<ModUpdateResult>> {
+    let updates = check_updates(lua, mods)?;
+    Ok(updates
+        .into_iter()
+        .map(|update| {
+            let error = update.apply(lua).err().map(|e| {
+                println!("Could not update mod {}: {}", update.id, e);
+                e.to_string()
+            });
+            ModUpdateResult { update, error }
+        })
+        .collect())
+}
+
 #[derive(PartialEq)]
 enum VisitFlag {
     Temporary,
@@ -299,3 +388,18 @@ pub fn sort_mods<'a>(lua: &'a Lua, mods_table: LuaTable<'a>) -> LuaResult<LuaTab
 
     Ok(sorted_mods_table)
 }
+
+// Ids of the enabled mods of a table returned by sort_mods, in the order they are loaded
+pub fn load_order(mods: LuaTable) -> LuaResult<Vec<String>> {
+    let mut load_order: Vec<(usize, String)> = Vec::new();
+    for pair in mods.pairs::<String, LuaTable>() {
+        let (id, mod_table) = pair?;
+        if mod_table.get::<_, Option<bool>>("enabled")? == Some(false) {
+            continue;
+        }
+        load_order.push((mod_table.get::<_, Option<usize>>("order")?.unwrap_or(0), id));
+    }
+    // sort_mods gives the mods loaded first the highest order
+    load_order.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    Ok(load_order.into_iter().map(|(_, id)| id).collect())
+}
@@ -0,0 +1,213 @@
+use crate::core::get_love_dir;
+use crate::mods::load_order;
+use crate::structs::overlayentry::OverlayEntry;
+use mlua::prelude::{LuaResult, LuaTable};
+use mlua::{Function, Lua, MultiValue, Table, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+// folder of a mod whose files are laid over the game's, by path
+const OVERLAY_DIR: &str = "overlay";
+
+// love functions taking the path of a game file as first argument
+const WRAPPED_FUNCTIONS: [(&str, &str); 8] = [
+    ("filesystem", "getInfo"),
+    ("filesystem", "read"),
+    ("filesystem", "load"),
+    ("filesystem", "lines"),
+    ("filesystem", "newFileData"),
+    ("graphics", "newImage"),
+    ("graphics", "newShader"),
+    ("audio", "newSource"),
+];
+
+// Files registered by mods over game paths, kept in the Lua state
+#[derive(Debug, Default)]
+pub struct Overlay {
+    // game path -> (mod id, file relative to the save directory), in mod load order
+    files: BTreeMap<String, Vec<(String, String)>>,
+    installed: bool,
+}
+
+impl Overlay {
+    // Registers `file` for `path`, a mod registered later takes precedence
+    pub fn register(&mut self, path: &str, mod_id: &str, file: &str) {
+        let owners = self.files.entry(normalize_path(path)).or_default();
+        owners.retain(|(owner, _)| owner != mod_id);
+        owners.push((mod_id.to_string(), file.to_string()));
+    }
+
+    // The file standing in for `path`, if any mod registered one
+    pub fn resolve(&self, path: &str) -> Option<&str> {
+        self.files
+            .get(&normalize_path(path))
+            .and_then(|owners| owners.last())
+            .map(|(_, file)| file.as_str())
+    }
+
+    pub fn entries(&self) -> Vec<OverlayEntry> {
+        self.files
+            .iter()
+            .filter_map(|(path, owners)| {
+                let ((mod_id, file), overridden) = owners.split_last()?;
+                Some(OverlayEntry {
+                    path: path.clone(),
+                    mod_id: mod_id.clone(),
+                    file: file.clone(),
+                    overridden: overridden.iter().map(|(owner, _)| owner.clone()).collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+// Game paths use forward slashes and no leading `./` or `/`
+pub fn normalize_path(path: &str) -> String {
+    path.replace('\\', "/")
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+// Paths of every file under `dir`, relative to it
+pub fn scan_overlay_dir(dir: &Path) -> Vec<String> {
+    fn scan(dir: &Path, prefix: &str, files: &mut Vec<String>) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+            if path.is_dir() {
+                scan(&path, &format!("{}{}/", prefix, name), files);
+            } else {
+                files.push(format!("{}{}", prefix, name));
+            }
+        }
+    }
+
+    let mut files = Vec::new();
+    scan(dir, "", &mut files);
+    files.sort();
+    files
+}
+
+fn with_overlay<T>(lua: &Lua, f: impl FnOnce(&mut Overlay) -> T) -> T {
+    if lua.app_data_ref::<Overlay>().is_none() {
+        lua.set_app_data(Overlay::default());
+    }
+    let mut overlay = lua.app_data_mut::<Overlay>().unwrap();
+    f(&mut overlay)
+}
+
+// Registers the overlay folders of the enabled mods in `mods` (as returned by sort_mods) in
+// load order, and installs the overlay. Returns every overlaid path with its owner.
+pub fn load_overlays(lua: &Lua, mods: LuaTable) -> LuaResult<Vec<OverlayEntry>> {
+    let love_dir = get_love_dir(lua)?;
+    for mod_id in load_order(mods)? {
+        let dir = format!("{}/mods/{}/{}", love_dir, mod_id, OVERLAY_DIR);
+        for path in scan_overlay_dir(Path::new(&dir)) {
+            let file = format!("mods/{}/{}/{}", mod_id, OVERLAY_DIR, path);
+            with_overlay(lua, |overlay| overlay.register(&path, &mod_id, &file));
+        }
+    }
+    install_overlay(lua)?;
+
+    let entries = with_overlay(lua, |overlay| overlay.entries());
+    for entry in entries.iter().filter(|entry| !entry.overridden.is_empty()) {
+        println!(
+            "{} is overridden by {} over {}",
+            entry.path,
+            entry.mod_id,
+            entry.overridden.join(", ")
+        );
+    }
+    Ok(entries)
+}
+
+// Registers `file`, relative to the mod's folder, for the game path `path`
+pub fn overlay_register(lua: &Lua, mod_id: String, path: String, file: String) -> LuaResult<()> {
+    let file = format!("mods/{}/{}", mod_id, normalize_path(&file));
+    with_overlay(lua, |overlay| overlay.register(&path, &mod_id, &file));
+    install_overlay(lua)
+}
+
+pub fn overlay_resolve(lua: &Lua, path: String) -> Option<String> {
+    with_overlay(lua, |overlay| {
+        overlay.resolve(&path).map(|file| file.to_string())
+    })
+}
+
+pub fn overlay_files(lua: &Lua) -> Vec<OverlayEntry> {
+    with_overlay(lua, |overlay| overlay.entries())
+}
+
+// Routes `require` and the love loaders in WRAPPED_FUNCTIONS through the overlay, once
+fn install_overlay(lua: &Lua) -> LuaResult<()> {
+    if with_overlay(lua, |overlay| {
+        std::mem::replace(&mut overlay.installed, true)
+    }) {
+        return Ok(());
+    }
+
+    // Lua modules, `require "a.b"` looks for `a/b.lua`
+    let loader = lua.create_function(|lua, name: String| {
+        let path = format!("{}.lua", name.replace('.', "/"));
+        let file = match overlay_resolve(lua, path.clone()) {
+            Some(file) => file,
+            None => {
+                return Ok(Value::String(
+                    lua.create_string(format!("\n\tno overlay file '{}'", path))?,
+                ))
+            }
+        };
+        let code = fs::read_to_string(format!("{}/{}", get_love_dir(lua)?, file))?;
+        let chunk = lua
+            .load(code)
+            .set_name(format!("@{}", path))
+            .into_function()?;
+        Ok(Value::Function(chunk))
+    })?;
+    if let Some(package) = lua.globals().get::<_, Option<Table>>("package")? {
+        let loaders = match package.get::<_, Option<Table>>("loaders")? {
+            Some(loaders) => Some(loaders),
+            None => package.get::<_, Option<Table>>("searchers")?,
+        };
+        if let Some(loaders) = loaders {
+            // right after package.preload
+            let table: Table = lua.globals().get("table")?;
+            let insert: Function = table.get("insert")?;
+            insert.call::<_, ()>((loaders, 2, loader))?;
+        }
+    }
+
+    let love = match lua.globals().get::<_, Option<Table>>("love")? {
+        Some(love) => love,
+        None => return Ok(()),
+    };
+    for (module, name) in WRAPPED_FUNCTIONS.iter() {
+        let module = match love.get::<_, Option<Table>>(*module)? {
+            Some(module) => module,
+            None => continue,
+        };
+        let original = match module.get::<_, Option<Function>>(*name)? {
+            Some(original) => lua.create_registry_value(original)?,
+            None => continue,
+        };
+        let wrapper = lua.create_function(move |lua, args: MultiValue| {
+            let mut args = args.into_vec();
+            if let Some(Value::String(path)) = args.first() {
+                if let Some(file) = overlay_resolve(lua, path.to_str()?.to_string()) {
+                    args[0] = Value::String(lua.create_string(file)?);
+                }
+            }
+            let original: Function = lua.registry_value(&original)?;
+            original.call::<_, MultiValue>(MultiValue::from_vec(args))
+        })?;
+        module.set(*name, wrapper)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,382 @@
+use crate::core::{get_love_dir, inject, inject_at};
+use crate::lua::lexer::Span;
+use crate::lua::patch::{edited_regions, Anchor, AstTarget, Edit, Position, TextMode, TextTarget};
+use crate::mods::load_order;
+use crate::sources::game_version;
+use crate::structs::patchrecord::{PatchConflict, PatchKind, PatchRecord, PatchResult};
+use crate::utils::validate_schema;
+use mlua::prelude::{LuaResult, LuaTable};
+use mlua::Lua;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Every patch applied to game_state, kept next to it in the Lua state
+#[derive(Debug, Default)]
+pub struct PatchRegistry {
+    pub patches: Vec<PatchRecord>,
+    pub conflicts: Vec<PatchConflict>,
+    // unpatched source of every patched function, by file and function name
+    pub originals: HashMap<(String, String), String>,
+    next_order: usize,
+}
+
+impl PatchRegistry {
+    // Records a patch applied to `file`/`function` as `edits` of its previous source. Regions
+    // written by earlier patches are moved along, and the conflicts with other mods whose code
+    // was touched are returned. `order` keeps the place of a patch that is applied again.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &mut self,
+        mod_id: &str,
+        file: &str,
+        function: &str,
+        kind: PatchKind,
+        insert: &str,
+        edits: &[Edit],
+        order: Option<usize>,
+    ) -> Vec<PatchConflict> {
+        let mut conflicts = Vec::new();
+        for patch in self.patches.iter_mut() {
+            if patch.file != file || patch.function != function {
+                continue;
+            }
+            let overlaps = edits.iter().any(|edit| {
+                patch
+                    .regions
+                    .iter()
+                    .any(|region| touches(edit.span, *region))
+            });
+            if overlaps && patch.mod_id != mod_id {
+                conflicts.push(PatchConflict {
+                    file: file.to_string(),
+                    function: function.to_string(),
+                    mod_id: mod_id.to_string(),
+                    other_mod_id: patch.mod_id.clone(),
+                    message: format!(
+                        "{} patched code of {} written by {}",
+                        mod_id, function, patch.mod_id
+                    ),
+                });
+            }
+            for edit in edits.iter().rev() {
+                for region in patch.regions.iter_mut() {
+                    *region = shift(*region, edit);
+                }
+            }
+        }
+
+        let regions = edited_regions(edits);
+        let order = order.unwrap_or_else(|| {
+            self.next_order += 1;
+            self.next_order - 1
+        });
+        self.patches.push(PatchRecord {
+            order,
+            mod_id: mod_id.to_string(),
+            file: file.to_string(),
+            function: function.to_string(),
+            kind,
+            insert: insert.to_string(),
+            count: edits.len(),
+            regions,
+        });
+        self.patches.sort_by_key(|patch| patch.order);
+        self.add_conflicts(conflicts)
+    }
+
+    // Records a patch that could not be applied to a function other mods already patched, their
+    // changes are the likely cause
+    pub fn record_failure(
+        &mut self,
+        mod_id: &str,
+        file: &str,
+        function: &str,
+        error: &str,
+    ) -> Vec<PatchConflict> {
+        let conflicts = self
+            .patched_by(file, function, mod_id)
+            .into_iter()
+            .map(|other| PatchConflict {
+                file: file.to_string(),
+                function: function.to_string(),
+                mod_id: mod_id.to_string(),
+                message: format!(
+                    "{} failed to patch {} after {} patched it: {}",
+                    mod_id, function, other, error
+                ),
+                other_mod_id: other,
+            })
+            .collect();
+        self.add_conflicts(conflicts)
+    }
+
+    // Mods other than `mod_id` that patched `file`/`function`, in the order they first did
+    pub fn patched_by(&self, file: &str, function: &str, mod_id: &str) -> Vec<String> {
+        let mut mods: Vec<String> = Vec::new();
+        for patch in self.patches.iter() {
+            if patch.file == file
+                && patch.function == function
+                && patch.mod_id != mod_id
+                && !mods.contains(&patch.mod_id)
+            {
+                mods.push(patch.mod_id.clone());
+            }
+        }
+        mods
+    }
+
+    // Remembers the source of a function before its first patch
+    pub fn keep_original(&mut self, file: &str, function: &str, code: &str) {
+        self.originals
+            .entry((file.to_string(), function.to_string()))
+            .or_insert_with(|| code.to_string());
+    }
+
+    // Drops every patch of `file`/`function`, returning its original source and the patches
+    // in the order they were applied
+    pub fn forget(&mut self, file: &str, function: &str) -> (Option<String>, Vec<PatchRecord>) {
+        let original = self
+            .originals
+            .remove(&(file.to_string(), function.to_string()));
+        let (forgotten, kept) = self
+            .patches
+            .drain(..)
+            .partition(|patch| patch.file == file && patch.function == function);
+        self.patches = kept;
+        self.conflicts
+            .retain(|conflict| conflict.file != file || conflict.function != function);
+        (original, forgotten)
+    }
+
+    // Functions patched by `mod_id` as (file, function), in the order they were first patched
+    pub fn functions_patched_by(&self, mod_id: &str) -> Vec<(String, String)> {
+        let mut functions: Vec<(String, String)> = Vec::new();
+        for patch in self.patches.iter().filter(|patch| patch.mod_id == mod_id) {
+            let function = (patch.file.clone(), patch.function.clone());
+            if !functions.contains(&function) {
+                functions.push(function);
+            }
+        }
+        functions
+    }
+
+    pub fn forget_conflicts_of(&mut self, mod_id: &str) {
+        self.conflicts
+            .retain(|conflict| conflict.mod_id != mod_id && conflict.other_mod_id != mod_id);
+    }
+
+    // Keeps one conflict per pair of mods and function
+    fn add_conflicts(&mut self, conflicts: Vec<PatchConflict>) -> Vec<PatchConflict> {
+        let mut added = Vec::new();
+        for conflict in conflicts {
+            let known = self.conflicts.iter().chain(added.iter()).any(|known| {
+                known.file == conflict.file
+                    && known.function == conflict.function
+                    && known.mod_id == conflict.mod_id
+                    && known.other_mod_id == conflict.other_mod_id
+            });
+            if !known {
+                added.push(conflict);
+            }
+        }
+        self.conflicts.extend(added.iter().cloned());
+        added
+    }
+}
+
+fn delta(edit: &Edit) -> isize {
+    edit.text.len() as isize - (edit.span.end - edit.span.start) as isize
+}
+
+// Whether `edit` changes code inside `region`, inserting right at its edges does not
+fn touches(edit: Span, region: Span) -> bool {
+    if edit.start == edit.end {
+        region.start < edit.start && edit.start < region.end
+    } else {
+        edit.start < region.end && region.start < edit.end
+    }
+}
+
+// Moves `region` to its place in the source after `edit`
+fn shift(region: Span, edit: &Edit) -> Span {
+    let delta = delta(edit);
+    if region.end <= edit.span.start {
+        region
+    } else if region.start >= edit.span.end {
+        let start = (region.start as isize + delta) as usize;
+        let end = (region.end as isize + delta) as usize;
+        Span::new(start, end)
+    } else {
+        // partly rewritten, the region grows to cover the new text
+        let end = (region.end as isize + delta).max((edit.span.start + edit.text.len()) as isize);
+        Span::new(region.start.min(edit.span.start), end as usize)
+    }
+}
+
+pub fn with_registry<T>(lua: &Lua, f: impl FnOnce(&mut PatchRegistry) -> T) -> T {
+    if lua.app_data_ref::<PatchRegistry>().is_none() {
+        lua.set_app_data(PatchRegistry::default());
+    }
+    let mut registry = lua.app_data_mut::<PatchRegistry>().unwrap();
+    f(&mut registry)
+}
+
+pub fn list_patches(lua: &Lua) -> Vec<PatchRecord> {
+    with_registry(lua, |registry| registry.patches.clone())
+}
+
+pub fn patch_conflicts(lua: &Lua) -> Vec<PatchConflict> {
+    with_registry(lua, |registry| registry.conflicts.clone())
+}
+
+// One entry of a mod's patches.json, either a text patch (`find`) or an AST one (`at`)
+#[derive(Debug, Deserialize, Clone)]
+pub struct PatchEntry {
+    pub description: Option<String>,
+    pub file: String,
+    pub function: String,
+    pub payload: String,
+    #[serde(default)]
+    pub game_versions: Vec<String>,
+    pub find: Option<String>,
+    pub mode: Option<String>,
+    pub occurrence: Option<usize>,
+    #[serde(default)]
+    pub regex: bool,
+    pub at: Option<String>,
+    pub name: Option<String>,
+    pub position: Option<String>,
+    pub nth: Option<usize>,
+}
+
+impl PatchEntry {
+    pub fn kind(&self) -> Result<PatchKind, String> {
+        if let Some(find) = &self.find {
+            return Ok(PatchKind::Text {
+                find: find.clone(),
+                target: TextTarget {
+                    mode: TextMode::parse(self.mode.as_deref())?,
+                    occurrence: self.occurrence,
+                    regex: self.regex,
+                },
+            });
+        }
+        let at = self.at.as_deref().ok_or("Patch needs either find or at")?;
+        Ok(PatchKind::Ast(AstTarget {
+            anchor: Anchor::parse(at, self.name.clone())?,
+            position: Position::parse(self.position.as_deref())?,
+            nth: self.nth,
+        }))
+    }
+}
+
+pub fn parse_patch_file(patches: &str) -> Result<Vec<PatchEntry>, String> {
+    let schema = include_bytes!("schema/patches.schema.json");
+    let schema = String::from_utf8(schema.to_vec()).unwrap();
+    let validation = validate_schema(schema, patches.to_string());
+    if validation != "valid" {
+        return Err(format!("Invalid patches.json: {}", validation));
+    }
+    serde_json::from_str(patches).map_err(|e| format!("Error parsing patches.json: {}", e))
+}
+
+// Applies the patches.json of every enabled mod in `mods` (as returned by sort_mods), in load
+// order, and returns the outcome of each patch
+pub fn apply_patch_files(lua: &Lua, mods: LuaTable) -> LuaResult<Vec<PatchResult>> {
+    let love_dir = get_love_dir(lua)?;
+    let game_version = game_version(lua);
+    let mut results = Vec::new();
+    for mod_id in load_order(mods)? {
+        let patch_file = format!("{}/mods/{}/patches.json", love_dir, mod_id);
+        if !std::path::Path::new(&patch_file).exists() {
+            continue;
+        }
+        let entries = std::fs::read_to_string(&patch_file)
+            .map_err(|e| format!("Error reading patches.json: {}", e))
+            .and_then(|patches| parse_patch_file(&patches));
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("Patches of {} not applied: {}", mod_id, e);
+                results.push(PatchResult {
+                    mod_id,
+                    index: 0,
+                    description: None,
+                    file: None,
+                    function: None,
+                    count: 0,
+                    error: Some(e),
+                });
+                continue;
+            }
+        };
+
+        let mut failed = 0;
+        for (i, entry) in entries.iter().enumerate() {
+            let applied = match entry.kind() {
+                _ if !game_version.matches(&entry.game_versions) => Err(format!(
+                    "Not supported on game version {}",
+                    game_version.fingerprint()
+                )),
+                Ok(kind) => apply_entry(lua, &mod_id, entry, kind).map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = &applied {
+                failed += 1;
+                println!("Patch {} of {} failed: {}", i + 1, mod_id, e);
+            }
+            results.push(PatchResult {
+                mod_id: mod_id.clone(),
+                index: i + 1,
+                description: entry.description.clone(),
+                file: Some(entry.file.clone()),
+                function: Some(entry.function.clone()),
+                count: *applied.as_ref().unwrap_or(&0),
+                error: applied.err(),
+            });
+        }
+        println!(
+            "Patches of {}: {} applied, {} failed",
+            mod_id,
+            entries.len() - failed,
+            failed
+        );
+    }
+    Ok(results)
+}
+
+// (mod id, file, function) of every applied patch and of every patches.json entry of the
+// installed mods
+pub fn installed_patch_targets(lua: &Lua) -> LuaResult<Vec<(String, String, String)>> {
+    let mut targets: Vec<(String, String, String)> = list_patches(lua)
+        .into_iter()
+        .map(|patch| (patch.mod_id, patch.file, patch.function))
+        .collect();
+    let mods_dir = format!("{}/mods", get_love_dir(lua)?);
+    let mod_dirs = match std::fs::read_dir(&mods_dir) {
+        Ok(mod_dirs) => mod_dirs,
+        Err(_) => return Ok(targets),
+    };
+    for mod_dir in mod_dirs.flatten() {
+        let mod_id = mod_dir.file_name().to_string_lossy().to_string();
+        let entries = std::fs::read_to_string(mod_dir.path().join("patches.json"))
+            .ok()
+            .and_then(|patches| parse_patch_file(&patches).ok());
+        for entry in entries.unwrap_or_default() {
+            targets.push((mod_id.clone(), entry.file, entry.function));
+        }
+    }
+    Ok(targets)
+}
+
+fn apply_entry(lua: &Lua, mod_id: &str, entry: &PatchEntry, kind: PatchKind) -> LuaResult<usize> {
+    let (file, function) = (entry.file.clone(), entry.function.clone());
+    let mod_id = Some(mod_id.to_string());
+    let payload = entry.payload.clone();
+    match kind {
+        PatchKind::Text { find, target } => {
+            inject(lua, file, function, find, payload, target, mod_id)
+        }
+        PatchKind::Ast(target) => inject_at(lua, file, function, target, payload, mod_id),
+    }
+}
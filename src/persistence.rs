@@ -0,0 +1,108 @@
+use crate::core::get_love_dir;
+use mlua::prelude::{LuaError, LuaResult, LuaString};
+use mlua::Lua;
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    PathBuf::from(temp)
+}
+
+// Writes to a temp file, syncs it and renames it over the target so readers never see a
+// half-written file. The previous contents are kept as a single `.bak` generation.
+pub fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let temp = temp_path(path);
+    {
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(contents.as_ref())?;
+        file.sync_all()?;
+    }
+
+    if path.exists() {
+        let backup = backup_path(path);
+        fs::copy(path, &backup)?;
+        // the backup has to be on disk before the rename replaces the file it copies
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&backup)?
+            .sync_all()?;
+    }
+    fs::rename(&temp, path)?;
+
+    // make the rename itself durable, directories cannot be opened for syncing on windows
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Reads and parses a file written by `write_atomic`, falling back to the `.bak` generation
+// when the primary file is missing or cannot be parsed, in which case the primary is restored
+// from it. Returns None if neither file exists.
+pub fn read_with_backup<P, T, E, F>(path: P, parse: F) -> std::io::Result<Option<Result<T, E>>>
+where
+    P: AsRef<Path>,
+    F: Fn(&str) -> Result<T, E>,
+{
+    let path = path.as_ref();
+    let backup = backup_path(path);
+    let primary = if path.exists() {
+        Some(parse(&fs::read_to_string(path)?))
+    } else {
+        None
+    };
+    match primary {
+        Some(Ok(value)) => Ok(Some(Ok(value))),
+        primary => {
+            if backup.exists() {
+                if let Ok(value) = parse(&fs::read_to_string(&backup)?) {
+                    fs::copy(&backup, path)?;
+                    println!(
+                        "Recovered {} from backup {}",
+                        path.display(),
+                        backup.display()
+                    );
+                    return Ok(Some(Ok(value)));
+                }
+            }
+            Ok(primary)
+        }
+    }
+}
+
+// Writes a file of the save directory with write_atomic, for profiles and other state the Lua
+// side saves. `name` is relative to the save directory, e.g. `1/profile.jkr`.
+pub fn write_save_file(lua: &Lua, name: String, contents: LuaString) -> LuaResult<()> {
+    let relative = Path::new(&name);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(LuaError::RuntimeError(format!(
+            "Not a path in the save directory: {}",
+            name
+        )));
+    }
+    let path = Path::new(&get_love_dir(lua)?).join(relative);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(&path, contents.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,594 @@
+use crate::structs::serializeoptions::SerializeOptions;
+use mlua::prelude::{LuaError, LuaResult};
+use mlua::{Lua, Table, Value};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::collections::{HashMap, HashSet};
+use std::ffi::c_void;
+use std::fmt;
+
+// Conversion between Lua values and JSON, made so that json_to_lua(lua_to_json(v)) gives v back:
+// - a table whose keys are the integers 1..n, possibly with holes, is an array when at least
+//   half of the slots are set, holes being null. Any other table is an object.
+// - integer keys of objects are written as strings, and object keys that are integers written
+//   canonically (`3`, `-1`, not `03`) are read back as integer keys, so a string key "3" comes
+//   back as the integer 3
+// - NaN and the infinities are written `{"$number": "NaN" | "Infinity" | "-Infinity"}`
+// - strings that are not valid UTF-8 are written `{"$bytes": "<hex>"}`
+// - functions, userdata, threads and keys other than strings and integers can't be converted,
+//   the error gives their path, e.g. `$.jokers[2].calculate`
+// - with the refs option, a table met again is written `{"$ref": id}` and its first occurrence
+//   gets an `"$id": id` key, or is written `{"$id": id, "$items": [...]}` for an array. Integer
+//   `$ref` and `$id` are read back as the same table, in any order, since sorted keys can put a
+//   `$ref` before its table.
+// - keys of the data spelled like a tag, `$number`, `$bytes`, `$ref`, `$id`, `$items` and
+//   `$truncated` with any number of leading `$`, are written with one more `$` and read back
+//   with one less, so a table with a `$id` key is not taken for a tagged value
+
+pub const NUMBER_TAG: &str = "$number";
+pub const BYTES_TAG: &str = "$bytes";
+const REF_TAG: &str = "$ref";
+const ID_TAG: &str = "$id";
+const ITEMS_TAG: &str = "$items";
+const TRUNCATED_TAG: &str = "$truncated";
+const TAGS: [&str; 6] = [
+    NUMBER_TAG,
+    BYTES_TAG,
+    REF_TAG,
+    ID_TAG,
+    ITEMS_TAG,
+    TRUNCATED_TAG,
+];
+
+#[derive(Debug)]
+pub struct ConversionError {
+    // path segments from the innermost value outwards, e.g. `[2]` then `.jokers`
+    path: Vec<String>,
+    message: String,
+}
+
+impl ConversionError {
+    pub fn new(message: impl Into<String>) -> ConversionError {
+        ConversionError {
+            path: Vec::new(),
+            message: message.into(),
+        }
+    }
+
+    pub fn within(mut self, segment: String) -> ConversionError {
+        self.path.push(segment);
+        self
+    }
+
+    pub fn path(&self) -> String {
+        let mut path = "$".to_string();
+        for segment in self.path.iter().rev() {
+            path.push_str(segment);
+        }
+        path
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.path())
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+// How a key reads in an error path: `.name`, `["a key"]` or `[3]`
+pub fn path_segment(key: &JsonKey) -> String {
+    match key {
+        JsonKey::Index(index) => format!("[{}]", index),
+        JsonKey::Name(name) => {
+            let identifier = name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if identifier {
+                format!(".{}", name)
+            } else {
+                format!("[{}]", JsonValue::String(name.clone()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonKey {
+    Index(i64),
+    Name(String),
+}
+
+impl JsonKey {
+    // An object key as read back into Lua
+    pub fn parse(key: &str) -> JsonKey {
+        match key.parse::<i64>() {
+            Ok(index) if index.to_string() == key => JsonKey::Index(index),
+            _ => JsonKey::Name(key.to_string()),
+        }
+    }
+}
+
+// The length of the array a table with these keys is written as, None for an object
+pub fn array_length(keys: &[JsonKey]) -> Option<usize> {
+    let mut length = 0;
+    for key in keys {
+        match key {
+            JsonKey::Index(index) if *index >= 1 => length = length.max(*index as usize),
+            _ => return None,
+        }
+    }
+    if length == 0 || length > keys.len() * 2 {
+        return None;
+    }
+    Some(length)
+}
+
+pub fn special_number(number: f64) -> Option<&'static str> {
+    if number.is_nan() {
+        Some("NaN")
+    } else if number == f64::INFINITY {
+        Some("Infinity")
+    } else if number == f64::NEG_INFINITY {
+        Some("-Infinity")
+    } else {
+        None
+    }
+}
+
+pub fn encode_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn decode_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Whether `key` is a tag name with one or more `$`
+fn is_tag_key(key: &str) -> bool {
+    key.starts_with('$')
+        && TAGS
+            .iter()
+            .any(|tag| key.trim_start_matches('$') == &tag[1..])
+}
+
+// The object key a key of the data is written as
+fn escape_key(key: String) -> String {
+    if is_tag_key(&key) {
+        format!("${}", key)
+    } else {
+        key
+    }
+}
+
+// The key of the data an object key was written for
+fn unescape_key(key: String) -> String {
+    if key.starts_with("$$") && is_tag_key(&key) {
+        key[1..].to_string()
+    } else {
+        key
+    }
+}
+
+// A tagged value, `{"$number": ...}` or `{"$bytes": ...}`, decoded
+pub enum Tagged {
+    Number(f64),
+    Bytes(Vec<u8>),
+}
+
+pub fn tagged(object: &serde_json::Map<String, JsonValue>) -> Option<Tagged> {
+    if object.len() != 1 {
+        return None;
+    }
+    let (tag, value) = object.iter().next()?;
+    let value = value.as_str()?;
+    match tag.as_str() {
+        NUMBER_TAG => match value {
+            "NaN" => Some(Tagged::Number(f64::NAN)),
+            "Infinity" => Some(Tagged::Number(f64::INFINITY)),
+            "-Infinity" => Some(Tagged::Number(f64::NEG_INFINITY)),
+            _ => None,
+        },
+        BYTES_TAG => decode_bytes(value).map(Tagged::Bytes),
+        _ => None,
+    }
+}
+
+pub fn tag(name: &str, value: String) -> JsonValue {
+    let mut object = serde_json::Map::new();
+    object.insert(name.to_string(), JsonValue::String(value));
+    JsonValue::Object(object)
+}
+
+// The conversion with the default options
+pub fn lua_value_to_json_value(value: &Value) -> Result<JsonValue, ConversionError> {
+    to_json_value(value, &SerializeOptions::default())
+}
+
+pub fn to_json_value(
+    value: &Value,
+    options: &SerializeOptions,
+) -> Result<JsonValue, ConversionError> {
+    let mut encoder = Encoder {
+        options,
+        stack: Vec::new(),
+        shared: HashSet::new(),
+        ids: HashMap::new(),
+    };
+    if options.refs {
+        if let Value::Table(table) = value {
+            let mut seen = HashSet::new();
+            shared_tables(table, 0, options, &mut seen, &mut encoder.shared)?;
+        }
+    }
+    Ok(encoder.value(value, 0)?.unwrap_or(JsonValue::Null))
+}
+
+// Adds to `shared` the tables reachable more than once from `table`, down to the depth they
+// are written at
+fn shared_tables(
+    table: &Table,
+    depth: usize,
+    options: &SerializeOptions,
+    seen: &mut HashSet<*const c_void>,
+    shared: &mut HashSet<*const c_void>,
+) -> Result<(), ConversionError> {
+    if options.max_depth.is_some_and(|max_depth| depth > max_depth) {
+        return Ok(());
+    }
+    if !seen.insert(table.to_pointer()) {
+        shared.insert(table.to_pointer());
+        return Ok(());
+    }
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (_, value) = pair.map_err(|e| ConversionError::new(e.to_string()))?;
+        if let Value::Table(child) = value {
+            shared_tables(&child, depth + 1, options, seen, shared)?;
+        }
+    }
+    Ok(())
+}
+
+struct Encoder<'a> {
+    options: &'a SerializeOptions,
+    // the tables being written, from the root
+    stack: Vec<*const c_void>,
+    // with refs, the tables met more than once and the ids of the ones already written
+    shared: HashSet<*const c_void>,
+    ids: HashMap<*const c_void, i64>,
+}
+
+impl Encoder<'_> {
+    // None for an unsupported value that is skipped
+    fn value(&mut self, value: &Value, depth: usize) -> Result<Option<JsonValue>, ConversionError> {
+        let json = match value {
+            Value::Nil => JsonValue::Null,
+            Value::Boolean(b) => JsonValue::Bool(*b),
+            Value::Integer(i) => JsonValue::Number((*i).into()),
+            Value::Number(n) => match serde_json::Number::from_f64(match self.options.precision {
+                Some(digits) => round_significant(*n, digits),
+                None => *n,
+            }) {
+                Some(number) => JsonValue::Number(number),
+                None => tag(NUMBER_TAG, special_number(*n).unwrap().to_string()),
+            },
+            Value::String(s) => match s.to_str() {
+                Ok(s) => JsonValue::String(s.to_string()),
+                Err(_) => tag(BYTES_TAG, encode_bytes(s.as_bytes())),
+            },
+            Value::Table(table) => self.table(table, depth)?,
+            _ if self.options.skip_unsupported => return Ok(None),
+            other => {
+                return Err(ConversionError::new(format!(
+                    "Cannot convert a {}",
+                    other.type_name()
+                )))
+            }
+        };
+        Ok(Some(json))
+    }
+
+    fn table(&mut self, table: &Table, depth: usize) -> Result<JsonValue, ConversionError> {
+        let pointer = table.to_pointer();
+        if let Some(id) = self.ids.get(&pointer) {
+            let mut object = serde_json::Map::new();
+            object.insert(REF_TAG.to_string(), JsonValue::from(*id));
+            return Ok(JsonValue::Object(object));
+        }
+        if self.stack.contains(&pointer) {
+            return Err(ConversionError::new(
+                "Table contains itself, it can only be converted with refs",
+            ));
+        }
+        if self
+            .options
+            .max_depth
+            .is_some_and(|max_depth| depth > max_depth)
+        {
+            let mut object = serde_json::Map::new();
+            object.insert(TRUNCATED_TAG.to_string(), JsonValue::Bool(true));
+            return Ok(JsonValue::Object(object));
+        }
+        let id = if self.shared.contains(&pointer) {
+            let id = self.ids.len() as i64 + 1;
+            self.ids.insert(pointer, id);
+            Some(id)
+        } else {
+            None
+        };
+
+        self.stack.push(pointer);
+        let json = self.entries(table, depth);
+        self.stack.pop();
+        let json = json?;
+
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(json),
+        };
+        let mut object = serde_json::Map::new();
+        object.insert(ID_TAG.to_string(), JsonValue::from(id));
+        match json {
+            JsonValue::Object(map) => object.extend(map),
+            items => {
+                object.insert(ITEMS_TAG.to_string(), items);
+            }
+        }
+        Ok(JsonValue::Object(object))
+    }
+
+    fn entries(&mut self, table: &Table, depth: usize) -> Result<JsonValue, ConversionError> {
+        let mut entries = Vec::new();
+        for pair in table.clone().pairs::<Value, Value>() {
+            let (key, value) = pair.map_err(|e| ConversionError::new(e.to_string()))?;
+            let key = match key {
+                Value::Integer(index) => JsonKey::Index(index),
+                Value::String(name) => match name.to_str() {
+                    Ok(name) => JsonKey::Name(name.to_string()),
+                    Err(_) if self.options.skip_unsupported => continue,
+                    Err(_) => return Err(ConversionError::new("Cannot convert a non UTF-8 key")),
+                },
+                _ if self.options.skip_unsupported => continue,
+                other => {
+                    return Err(ConversionError::new(format!(
+                        "Cannot convert a {} key",
+                        other.type_name()
+                    )))
+                }
+            };
+            entries.push((key, value));
+        }
+
+        let keys: Vec<JsonKey> = entries.iter().map(|(key, _)| key.clone()).collect();
+        if let Some(length) = array_length(&keys) {
+            let mut array = vec![JsonValue::Null; length];
+            for (key, value) in entries {
+                let value = self
+                    .value(&value, depth + 1)
+                    .map_err(|e| e.within(path_segment(&key)))?;
+                if let (JsonKey::Index(index), Some(value)) = (key, value) {
+                    array[index as usize - 1] = value;
+                }
+            }
+            return Ok(JsonValue::Array(array));
+        }
+
+        let mut map = serde_json::Map::new();
+        for (key, value) in entries {
+            let name = match &key {
+                JsonKey::Index(index) => index.to_string(),
+                JsonKey::Name(name) => escape_key(name.clone()),
+            };
+            // `t[1]` and `t["1"]` would both be written "1"
+            if map.contains_key(&name) {
+                return Err(ConversionError::new(format!(
+                    "Key {} is both an integer and a string",
+                    name
+                )));
+            }
+            let value = self
+                .value(&value, depth + 1)
+                .map_err(|e| e.within(path_segment(&key)))?;
+            if let Some(value) = value {
+                map.insert(name, value);
+            }
+        }
+        Ok(JsonValue::Object(map))
+    }
+}
+
+pub fn json_value_to_lua_value(lua: &Lua, value: JsonValue) -> LuaResult<Value> {
+    let mut decoder = Decoder {
+        lua,
+        tables: HashMap::new(),
+        defined: HashSet::new(),
+    };
+    let value = decoder.value(value)?;
+    let mut missing: Vec<&i64> = decoder
+        .tables
+        .keys()
+        .filter(|id| !decoder.defined.contains(*id))
+        .collect();
+    missing.sort();
+    match missing.first() {
+        Some(id) => Err(LuaError::RuntimeError(format!("$ref {} has no table", id))),
+        None => Ok(value),
+    }
+}
+
+struct Decoder<'lua> {
+    lua: &'lua Lua,
+    // tables by id, a "$ref" met before the "$id" of its table creates it empty
+    tables: HashMap<i64, Table<'lua>>,
+    // the ids whose table was read
+    defined: HashSet<i64>,
+}
+
+impl<'lua> Decoder<'lua> {
+    fn table(&mut self, id: i64) -> LuaResult<Table<'lua>> {
+        if let Some(table) = self.tables.get(&id) {
+            return Ok(table.clone());
+        }
+        let table = self.lua.create_table()?;
+        self.tables.insert(id, table.clone());
+        Ok(table)
+    }
+
+    fn value(&mut self, value: JsonValue) -> LuaResult<Value<'lua>> {
+        let lua = self.lua;
+        match value {
+            JsonValue::Null => Ok(Value::Nil),
+            JsonValue::Bool(b) => Ok(Value::Boolean(b)),
+            JsonValue::Number(num) => {
+                if let Some(n) = num.as_i64() {
+                    Ok(Value::Integer(n))
+                } else if let Some(n) = num.as_f64() {
+                    Ok(Value::Number(n))
+                } else {
+                    Err(LuaError::RuntimeError("Invalid number".to_string()))
+                }
+            }
+            JsonValue::String(s) => Ok(Value::String(lua.create_string(&s)?)),
+            JsonValue::Array(arr) => {
+                let tbl = lua.create_table()?;
+                self.fill_array(&tbl, arr)?;
+                Ok(Value::Table(tbl))
+            }
+            JsonValue::Object(mut obj) => {
+                match tagged(&obj) {
+                    Some(Tagged::Number(n)) => return Ok(Value::Number(n)),
+                    Some(Tagged::Bytes(bytes)) => {
+                        return Ok(Value::String(lua.create_string(bytes)?))
+                    }
+                    None => {}
+                }
+                // ids are integers, a string "$ref" like JSON schema's is a plain key
+                if let Some(id) = obj.get(REF_TAG).and_then(JsonValue::as_i64) {
+                    if obj.len() == 1 {
+                        return Ok(Value::Table(self.table(id)?));
+                    }
+                }
+                let mut tbl = lua.create_table()?;
+                if let Some(id) = obj.get(ID_TAG).and_then(JsonValue::as_i64) {
+                    if !self.defined.insert(id) {
+                        return Err(LuaError::RuntimeError(format!("Duplicate $id {}", id)));
+                    }
+                    obj.shift_remove(ID_TAG);
+                    tbl = self.table(id)?;
+                    if let Some(JsonValue::Array(items)) = obj.get(ITEMS_TAG) {
+                        if obj.len() == 1 {
+                            self.fill_array(&tbl, items.clone())?;
+                            return Ok(Value::Table(tbl));
+                        }
+                    }
+                }
+                for (key, value) in obj.into_iter() {
+                    let lua_value = self.value(value)?;
+                    match JsonKey::parse(&unescape_key(key)) {
+                        JsonKey::Index(index) => tbl.raw_set(index, lua_value)?,
+                        JsonKey::Name(name) => tbl.raw_set(name, lua_value)?,
+                    }
+                }
+                Ok(Value::Table(tbl))
+            }
+        }
+    }
+
+    fn fill_array(&mut self, tbl: &Table<'lua>, arr: Vec<JsonValue>) -> LuaResult<()> {
+        for (i, elem) in arr.into_iter().enumerate() {
+            // null leaves a hole
+            let lua_value = self.value(elem)?;
+            tbl.raw_set(i + 1, lua_value)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn round_significant(number: f64, digits: usize) -> f64 {
+    if !number.is_finite() {
+        return number;
+    }
+    let digits = digits.clamp(1, 17);
+    format!("{:.*e}", digits - 1, number)
+        .parse()
+        .unwrap_or(number)
+}
+
+pub fn sorted_keys(value: JsonValue) -> JsonValue {
+    match value {
+        JsonValue::Object(map) => {
+            let mut entries: Vec<(String, JsonValue)> = map.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            JsonValue::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, sorted_keys(value)))
+                    .collect(),
+            )
+        }
+        JsonValue::Array(items) => JsonValue::Array(items.into_iter().map(sorted_keys).collect()),
+        value => value,
+    }
+}
+
+// Non-ASCII characters only appear in strings, where \u escapes are equivalent
+pub fn escape_non_ascii(json: &str) -> String {
+    let mut escaped = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            escaped.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                escaped.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
+    }
+    escaped
+}
+
+// Writes a JSON value with the formatting options: indent, sort_keys and ascii
+pub fn write_json(value: &JsonValue, options: &SerializeOptions) -> Result<String, String> {
+    let sorted;
+    let value = if options.sort_keys {
+        sorted = sorted_keys(value.clone());
+        &sorted
+    } else {
+        value
+    };
+    let json = match options.indent {
+        Some(indent) => {
+            let indent = " ".repeat(indent);
+            let mut json = Vec::new();
+            let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+            let mut serializer = serde_json::Serializer::with_formatter(&mut json, formatter);
+            value
+                .serialize(&mut serializer)
+                .map_err(|e| e.to_string())?;
+            String::from_utf8(json).map_err(|e| e.to_string())?
+        }
+        None => serde_json::to_string(value).map_err(|e| e.to_string())?,
+    };
+    if options.ascii {
+        return Ok(escape_non_ascii(&json));
+    }
+    Ok(json)
+}
+
+pub fn lua_to_json(table: Value, options: SerializeOptions) -> LuaResult<String> {
+    let json_value = to_json_value(&table, &options)
+        .map_err(|e| LuaError::RuntimeError(format!("Error converting to JSON: {}", e)))?;
+    write_json(&json_value, &options).map_err(|e| LuaError::RuntimeError(format!("Error: {}", e)))
+}
+
+pub fn json_to_lua(lua: &Lua, json: String) -> LuaResult<Value> {
+    let value: JsonValue = serde_json::from_str(&json)
+        .map_err(|e| LuaError::RuntimeError(format!("Error parsing JSON: {}", e)))?;
+
+    json_value_to_lua_value(lua, value)
+}
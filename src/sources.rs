@@ -0,0 +1,281 @@
+use crate::core::get_love_dir;
+use crate::persistence::write_atomic;
+use crate::structs::gameversion::GameVersion;
+use crate::utils::{extract_functions, get_lua_files, minify_lua};
+use crate::VERSION;
+use mlua::prelude::LuaResult;
+use mlua::Lua;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::{env, fmt, fs};
+
+/// file name -> function name -> minified function code, the layout of game_state
+pub type SourceIndex = BTreeMap<String, BTreeMap<String, String>>;
+
+// 64-bit FNV-1a. Source hashes are published through game_version() and written in manifests,
+// so they can't come from DefaultHasher, whose output may change between Rust releases.
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Fnv1a {
+        Fnv1a(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+// Hash of the running game's sources, computed once per Lua state
+struct SourcesHash(Option<String>);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SourceIndexCache {
+    key: String,
+    files: SourceIndex,
+}
+
+pub fn build_source_index(files: HashMap<String, String>) -> SourceIndex {
+    let mut index = SourceIndex::new();
+    for (name, content) in files {
+        // inject minifies the code it looks for, so every function is stored minified
+        let functions = extract_functions(content)
+            .into_iter()
+            .map(|(fn_name, code)| (fn_name, minify_lua(code)))
+            .collect();
+        index.insert(name, functions);
+    }
+    index
+}
+
+/// The archive or file read_lua_sources could not read, and why
+#[derive(Debug)]
+pub struct SourceError {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl SourceError {
+    fn new(path: &Path, message: impl fmt::Display) -> SourceError {
+        SourceError {
+            path: path.to_path_buf(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+/// Reads the Lua sources of a game from a fused executable, a .love archive or an unpacked
+/// folder, keyed by their path without the `.lua` extension, e.g. `functions/common_events`.
+///
+/// ```no_run
+/// let files = balalib::read_lua_sources(std::path::Path::new("Balatro.exe")).unwrap();
+/// println!("{} files", files.len());
+/// ```
+pub fn read_lua_sources(path: &Path) -> Result<HashMap<String, String>, SourceError> {
+    let mut files = HashMap::new();
+    if path.is_dir() {
+        read_dir_sources(path, path, &mut files)?;
+        return Ok(files);
+    }
+
+    let file = fs::File::open(path).map_err(|e| SourceError::new(path, e))?;
+    let mut archive = zip::ZipArchive::new(file)
+        .map_err(|e| SourceError::new(path, format!("not a game archive ({})", e)))?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).map_err(|e| SourceError::new(path, e))?;
+        let name = match entry.name().strip_suffix(".lua") {
+            Some(name) if entry.is_file() => name.to_string(),
+            _ => continue,
+        };
+        let mut content = String::new();
+        entry
+            .read_to_string(&mut content)
+            .map_err(|e| SourceError::new(path, format!("{}.lua: {}", name, e)))?;
+        files.insert(name, content);
+    }
+    Ok(files)
+}
+
+fn read_dir_sources(
+    root: &Path,
+    dir: &Path,
+    files: &mut HashMap<String, String>,
+) -> Result<(), SourceError> {
+    let entries = fs::read_dir(dir).map_err(|e| SourceError::new(dir, e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| SourceError::new(dir, e))?.path();
+        if path.is_dir() {
+            read_dir_sources(root, &path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "lua") {
+            let content = fs::read_to_string(&path).map_err(|e| SourceError::new(&path, e))?;
+            let name = path.strip_prefix(root).unwrap().with_extension("");
+            let name: Vec<String> = name
+                .components()
+                .map(|part| part.as_os_str().to_string_lossy().to_string())
+                .collect();
+            files.insert(name.join("/"), content);
+        }
+    }
+    Ok(())
+}
+
+// Where get_lua_files reads the game from: the fused executable, or the source folder when
+// the game is started with `love <folder>`
+pub fn game_source_path() -> Option<PathBuf> {
+    let exe_path = env::current_exe().ok()?;
+    let exe_name = exe_path.file_name()?.to_str()?;
+    let from_folder = exe_name == "love" || exe_name == "love.exe";
+    #[cfg(target_os = "macos")]
+    let from_folder = from_folder || cfg!(debug_assertions);
+    if from_folder {
+        env::args().nth(1).map(PathBuf::from)
+    } else {
+        Some(exe_path)
+    }
+}
+
+// Hash of an archive's bytes, or of the names and contents of the Lua files in a folder
+pub fn hash_game_sources(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Fnv1a::default();
+    if path.is_dir() {
+        hash_dir(path, &mut hasher)?;
+    } else {
+        let mut file = fs::File::open(path)?;
+        let mut buffer = vec![0; 1 << 20];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.write(&buffer[..read]);
+        }
+    }
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+fn hash_dir(dir: &Path, hasher: &mut Fnv1a) -> std::io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            hash_dir(&path, hasher)?;
+        } else if path.extension().is_some_and(|ext| ext == "lua") {
+            hasher.write(path.to_string_lossy().as_bytes());
+            hasher.write(&fs::read(&path)?);
+        }
+    }
+    Ok(())
+}
+
+fn running_sources_hash(lua: &Lua) -> Option<String> {
+    if let Some(hash) = lua.app_data_ref::<SourcesHash>() {
+        return hash.0.clone();
+    }
+    let hash = match game_source_path().map(|path| hash_game_sources(&path)) {
+        Some(Ok(hash)) => Some(hash),
+        Some(Err(e)) => {
+            println!("Could not hash the game sources, not caching them: {}", e);
+            None
+        }
+        None => None,
+    };
+    lua.set_app_data(SourcesHash(hash.clone()));
+    hash
+}
+
+// The function index of the running game, read from the cache in the save directory when the
+// game archive did not change since it was written. The balalib version is part of the key so
+// that a new extractor rebuilds the index.
+pub fn load_source_index(lua: &Lua) -> LuaResult<SourceIndex> {
+    let cache_file = format!("{}/balalib/source_index.json", get_love_dir(lua)?);
+    let key = running_sources_hash(lua).map(|hash| format!("{}-{}", VERSION, hash));
+
+    if let Some(key) = &key {
+        let cached = fs::read_to_string(&cache_file)
+            .ok()
+            .and_then(|cache| serde_json::from_str::<SourceIndexCache>(&cache).ok());
+        if let Some(cached) = cached {
+            if &cached.key == key {
+                return Ok(cached.files);
+            }
+            println!("Game sources changed, rebuilding the source index");
+        }
+    }
+
+    let index = build_source_index(get_lua_files());
+    if let Some(key) = key {
+        let cache = SourceIndexCache {
+            key,
+            files: index.clone(),
+        };
+        let written = fs::create_dir_all(Path::new(&cache_file).parent().unwrap())
+            .and_then(|_| write_atomic(&cache_file, serde_json::to_string(&cache).unwrap()));
+        if let Err(e) = written {
+            println!("Could not write the source index cache: {}", e);
+        }
+    }
+    Ok(index)
+}
+
+// Fingerprints game sources by the first global VERSION they assign and a hash of every file
+pub fn fingerprint_sources(files: &HashMap<String, String>) -> GameVersion {
+    let version_pattern = regex::Regex::new(r#"(?m)^\s*VERSION\s*=\s*['"]([^'"]+)['"]"#).unwrap();
+    let mut names: Vec<&String> = files.keys().collect();
+    names.sort();
+    let mut hasher = Fnv1a::default();
+    let mut version = None;
+    for name in names {
+        hasher.write(name.as_bytes());
+        hasher.write(files[name].as_bytes());
+        if version.is_none() {
+            version = version_pattern
+                .captures(&files[name])
+                .map(|captures| captures[1].to_string());
+        }
+    }
+    GameVersion {
+        version,
+        hash: format!("{:016x}", hasher.finish()),
+    }
+}
+
+// The running game's version, computed once per Lua state. It is the fingerprint of the Lua
+// sources alone, so that it is the same on every platform and for tools reading the sources
+// with read_lua_sources, unlike the archive hash keying the source index cache.
+pub fn game_version(lua: &Lua) -> GameVersion {
+    if let Some(version) = lua.app_data_ref::<GameVersion>() {
+        return version.clone();
+    }
+    let mut version = fingerprint_sources(&get_lua_files());
+    if version.version.is_none() {
+        version.version = lua
+            .globals()
+            .get::<_, Option<String>>("VERSION")
+            .ok()
+            .flatten();
+    }
+    lua.set_app_data(version.clone());
+    version
+}
@@ -0,0 +1,128 @@
+use mlua::prelude::{LuaResult, LuaValue};
+use mlua::{IntoLua, Lua};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    String,
+    Number,
+    Integer,
+    Boolean,
+}
+
+impl ArgKind {
+    pub fn parse(kind: &str) -> Result<ArgKind, String> {
+        match kind {
+            "string" => Ok(ArgKind::String),
+            "number" => Ok(ArgKind::Number),
+            "integer" => Ok(ArgKind::Integer),
+            "bool" | "boolean" => Ok(ArgKind::Boolean),
+            _ => Err(format!("Unknown argument type: {}", kind)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArgKind::String => "string",
+            ArgKind::Number => "number",
+            ArgKind::Integer => "integer",
+            ArgKind::Boolean => "boolean",
+        }
+    }
+}
+
+// An argument of a command, written `<name:type>` in its usage, `[name:type]` when optional
+// and `<name...>` when it takes the rest of the line
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandArg {
+    pub name: String,
+    pub kind: ArgKind,
+    pub optional: bool,
+    pub rest: bool,
+}
+
+impl CommandArg {
+    // The argument as written in a usage string
+    pub fn describe(&self) -> String {
+        let rest = if self.rest { "..." } else { "" };
+        let kind = match self.kind {
+            ArgKind::String => String::new(),
+            kind => format!(":{}", kind.as_str()),
+        };
+        if self.optional {
+            format!("[{}{}{}]", self.name, kind, rest)
+        } else {
+            format!("<{}{}{}>", self.name, kind, rest)
+        }
+    }
+}
+
+impl IntoLua<'_> for CommandArg {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("name", self.name)?;
+        table.set("type", self.kind.as_str())?;
+        table.set("optional", self.optional)?;
+        table.set("rest", self.rest)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandEntry {
+    pub name: String,
+    pub mod_id: String,
+    // dotted path of the Lua function run by the command
+    pub lua_path: String,
+    pub short_description: String,
+    pub usage: String,
+    pub args: Vec<CommandArg>,
+}
+
+impl IntoLua<'_> for CommandEntry {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("name", self.name)?;
+        table.set("mod_id", self.mod_id)?;
+        table.set("lua_path", self.lua_path)?;
+        table.set("short_description", self.short_description)?;
+        table.set("usage", self.usage)?;
+        table.set("args", self.args)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandConflict {
+    pub name: String,
+    // the mod the plain name runs, loaded before other_mod_id
+    pub mod_id: String,
+    pub other_mod_id: String,
+}
+
+impl IntoLua<'_> for CommandConflict {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("name", self.name)?;
+        table.set("mod_id", self.mod_id)?;
+        table.set("other_mod_id", self.other_mod_id)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+// What a console can offer for a partially typed line
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandCompletion {
+    // full lines the typed one can be completed to
+    pub candidates: Vec<String>,
+    // the usage of the arguments left to type, once the command is known
+    pub hint: Option<String>,
+}
+
+impl IntoLua<'_> for CommandCompletion {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("candidates", self.candidates)?;
+        table.set("hint", self.hint)?;
+        Ok(LuaValue::Table(table))
+    }
+}
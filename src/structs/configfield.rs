@@ -0,0 +1,54 @@
+use crate::serialization::json_value_to_lua_value;
+use mlua::prelude::{LuaResult, LuaValue};
+use mlua::{IntoLua, Lua};
+use serde_json::Value as JsonValue;
+
+#[derive(Debug, Clone)]
+pub struct ConfigField {
+    pub path: String,
+    pub key: String,
+    pub field_type: String,
+    pub label: String,
+    pub description: Option<String>,
+    pub minimum: Option<f64>,
+    pub maximum: Option<f64>,
+    // the bound itself is not allowed
+    pub exclusive_minimum: bool,
+    pub exclusive_maximum: bool,
+    pub step: Option<f64>,
+    pub options: Option<Vec<JsonValue>>,
+    pub default: Option<JsonValue>,
+    pub value: Option<JsonValue>,
+    pub fields: Vec<ConfigField>,
+}
+
+impl IntoLua<'_> for ConfigField {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("path", self.path)?;
+        table.set("key", self.key)?;
+        table.set("type", self.field_type)?;
+        table.set("label", self.label)?;
+        table.set("description", self.description)?;
+        table.set("minimum", self.minimum)?;
+        table.set("maximum", self.maximum)?;
+        table.set("exclusive_minimum", self.exclusive_minimum)?;
+        table.set("exclusive_maximum", self.exclusive_maximum)?;
+        table.set("step", self.step)?;
+        if let Some(options) = self.options {
+            let options = options
+                .into_iter()
+                .map(|option| json_value_to_lua_value(lua, option))
+                .collect::<LuaResult<Vec<LuaValue>>>()?;
+            table.set("options", options)?;
+        }
+        if let Some(default) = self.default {
+            table.set("default", json_value_to_lua_value(lua, default)?)?;
+        }
+        if let Some(value) = self.value {
+            table.set("value", json_value_to_lua_value(lua, value)?)?;
+        }
+        table.set("fields", self.fields)?;
+        Ok(LuaValue::Table(table))
+    }
+}
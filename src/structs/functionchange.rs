@@ -0,0 +1,43 @@
+use mlua::prelude::{LuaResult, LuaValue};
+use mlua::{IntoLua, Lua};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Changed => "changed",
+        }
+    }
+}
+
+/// A function that differs between two source indexes, see diff_sources
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionChange {
+    pub kind: ChangeKind,
+    pub file: String,
+    pub function: String,
+    // unified diff of the function, only for changed functions
+    pub diff: Option<String>,
+    // mods with patches targeting the function
+    pub patched_by: Vec<String>,
+}
+
+impl IntoLua<'_> for FunctionChange {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("kind", self.kind.as_str())?;
+        table.set("file", self.file)?;
+        table.set("function", self.function)?;
+        table.set("diff", self.diff)?;
+        table.set("patched_by", self.patched_by)?;
+        Ok(LuaValue::Table(table))
+    }
+}
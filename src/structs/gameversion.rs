@@ -0,0 +1,50 @@
+use mlua::prelude::{LuaResult, LuaValue};
+use mlua::{IntoLua, Lua};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameVersion {
+    // the game's own VERSION string, e.g. `1.0.1o-FULL`
+    pub version: Option<String>,
+    // hash of the game's Lua sources, tells apart builds sharing a VERSION
+    pub hash: String,
+}
+
+impl GameVersion {
+    // Whether one of `supported` names this build, either by its VERSION or a leading part of it
+    // that ends on a number, so `1.0.1` names `1.0.1o-FULL` and `1.0.1.2` but not `1.0.10`, or
+    // by its source hash. An empty list supports every build.
+    pub fn matches(&self, supported: &[String]) -> bool {
+        supported.is_empty()
+            || supported.iter().any(|supported| {
+                *supported == self.hash
+                    || self
+                        .version
+                        .as_ref()
+                        .is_some_and(|version| version_names(version, supported))
+            })
+    }
+
+    pub fn fingerprint(&self) -> String {
+        match &self.version {
+            Some(version) => format!("{}+{}", version, self.hash),
+            None => self.hash.clone(),
+        }
+    }
+}
+
+fn version_names(version: &str, supported: &str) -> bool {
+    match version.strip_prefix(supported) {
+        Some(rest) => !rest.starts_with(|c: char| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+impl IntoLua<'_> for GameVersion {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("fingerprint", self.fingerprint())?;
+        table.set("version", self.version)?;
+        table.set("hash", self.hash)?;
+        Ok(LuaValue::Table(table))
+    }
+}
@@ -0,0 +1,23 @@
+use mlua::prelude::{LuaResult, LuaValue};
+use mlua::{IntoLua, Lua};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalizationConflict {
+    pub lang: String,
+    // dotted path in G.localization, e.g. `descriptions.Joker.j_joker.name`
+    pub key: String,
+    // the mod whose text is used, loaded after other_mod_id
+    pub mod_id: String,
+    pub other_mod_id: String,
+}
+
+impl IntoLua<'_> for LocalizationConflict {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("lang", self.lang)?;
+        table.set("key", self.key)?;
+        table.set("mod_id", self.mod_id)?;
+        table.set("other_mod_id", self.other_mod_id)?;
+        Ok(LuaValue::Table(table))
+    }
+}
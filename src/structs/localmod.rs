@@ -1,9 +1,19 @@
-use crate::core::{get_love_dir, json_to_lua, lua_to_json};
+use crate::config::{
+    config_fields, merge_defaults, migrate, schema_defaults, set_path, Migration, UNVERSIONED,
+};
+use crate::core::get_love_dir;
 use crate::download_mod;
+use crate::formats::{parse_toml, write_toml};
+use crate::mods::ignore_mod_version;
+use crate::persistence::{backup_path, read_with_backup, write_atomic};
+use crate::serialization::{json_to_lua, lua_value_to_json_value, to_json_value, write_json};
 use crate::structs::modinfo::ModInfo;
+use crate::structs::serializeoptions::SerializeOptions;
+use crate::utils::{is_newer_version, validate_schema};
 use mlua::prelude::{LuaError, LuaResult, LuaValue};
-use mlua::{IntoLua, Lua};
+use mlua::{FromLua, IntoLua, Lua};
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ModCommand {
@@ -24,11 +34,32 @@ impl IntoLua<'_> for ModCommand {
     }
 }
 
+impl FromLua<'_> for ModCommand {
+    fn from_lua(value: LuaValue, _: &'_ Lua) -> LuaResult<Self> {
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => {
+                return Err(LuaError::RuntimeError(
+                    "Expected a command table".to_string(),
+                ))
+            }
+        };
+        Ok(ModCommand {
+            name: table.get("name")?,
+            lua_path: table.get("lua_path")?,
+            short_description: table.get("short_description")?,
+            usage: table.get("usage")?,
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LocalMod {
     pub id: String,
     #[serde(skip)]
     pub enabled: bool,
+    #[serde(skip)]
+    pub pinned: bool,
     pub name: String,
     pub version: String,
     pub description: Vec<String>,
@@ -39,6 +70,11 @@ pub struct LocalMod {
     pub max_balamod_version: Option<String>,
     pub balalib_version: Option<String>,
     pub commands: Option<Vec<ModCommand>>,
+    // game builds the mod supports, see GameVersion::matches
+    #[serde(default)]
+    pub game_versions: Vec<String>,
+    #[serde(skip)]
+    pub game_compatible: bool,
 }
 
 impl IntoLua<'_> for LocalMod {
@@ -49,10 +85,26 @@ impl IntoLua<'_> for LocalMod {
         let update_mod = local_mod.clone();
         let save_config = local_mod.clone();
         let load_config = local_mod.clone();
+        let pin_mod = local_mod.clone();
+        let unpin_mod = local_mod.clone();
+        let ignore_version = local_mod.clone();
+        let config_ui = local_mod.clone();
+        let set_config_value = local_mod.clone();
         table.set(
             "update",
             lua.create_function(move |lua, mods: Vec<ModInfo>| update_mod.update(lua, mods))?,
         )?;
+        table.set("pin", lua.create_function(move |lua, ()| pin_mod.pin(lua))?)?;
+        table.set(
+            "unpin",
+            lua.create_function(move |lua, ()| unpin_mod.unpin(lua))?,
+        )?;
+        table.set(
+            "ignore_version",
+            lua.create_function(move |lua, version: String| {
+                ignore_version.ignore_version(lua, version)
+            })?,
+        )?;
         table.set(
             "delete",
             lua.create_function(move |lua, ()| delete_mod.delete(lua))?,
@@ -65,14 +117,27 @@ impl IntoLua<'_> for LocalMod {
             "load_config",
             lua.create_function(move |lua, ()| load_config.load_config(lua))?,
         )?;
+        table.set(
+            "config_ui",
+            lua.create_function(move |lua, ()| config_ui.config_ui(lua))?,
+        )?;
+        table.set(
+            "set_config_value",
+            lua.create_function(move |lua, (path, value): (String, LuaValue)| {
+                set_config_value.set_config_value(lua, path, value)
+            })?,
+        )?;
         table.set("id", local_mod.id)?;
         table.set("name", local_mod.name)?;
         table.set("enabled", local_mod.enabled)?;
+        table.set("pinned", local_mod.pinned)?;
         table.set("version", local_mod.version)?;
         table.set("description", local_mod.description)?;
         table.set("author", local_mod.author)?;
         table.set("load_before", local_mod.load_before)?;
         table.set("load_after", local_mod.load_after)?;
+        table.set("game_versions", local_mod.game_versions)?;
+        table.set("game_compatible", local_mod.game_compatible)?;
         match local_mod.commands {
             Some(commands) => {
                 let commands: Vec<LuaValue> = commands
@@ -98,13 +163,20 @@ impl LocalMod {
         Ok(())
     }
 
-    pub fn update(&self, lua: &Lua, mods: Vec<ModInfo>) -> LuaResult<()> {
+    pub fn update(&self, lua: &Lua, mods: Vec<ModInfo>) -> LuaResult<bool> {
         let mod_info = mods.iter().find(|mod_info| mod_info.id == self.id);
         match mod_info {
             Some(mod_info) => {
+                if !is_newer_version(&self.version, &mod_info.version) {
+                    println!(
+                        "Mod {} is up to date ({} >= {})",
+                        self.id, self.version, mod_info.version
+                    );
+                    return Ok(false);
+                }
                 download_mod(lua, mod_info.clone())?;
                 println!("Updated mod: {}", self.id);
-                Ok(())
+                Ok(true)
             }
             None => {
                 println!("Mod not found in the repo: {}", self.id);
@@ -116,27 +188,234 @@ impl LocalMod {
         }
     }
 
+    pub fn pin(&self, lua: &Lua) -> LuaResult<()> {
+        let love_dir = get_love_dir(lua)?;
+        let mods_dir = format!("{}/mods", love_dir);
+        let mod_dir = format!("{}/{}", mods_dir, self.id);
+        write_atomic(format!("{}/pin.it", mod_dir), "")?;
+        Ok(())
+    }
+
+    pub fn unpin(&self, lua: &Lua) -> LuaResult<()> {
+        let love_dir = get_love_dir(lua)?;
+        let mods_dir = format!("{}/mods", love_dir);
+        let pin_file = format!("{}/{}/pin.it", mods_dir, self.id);
+        if std::path::Path::new(&pin_file).exists() {
+            std::fs::remove_file(pin_file)?;
+        }
+        Ok(())
+    }
+
+    pub fn ignore_version(&self, lua: &Lua, version: String) -> LuaResult<()> {
+        ignore_mod_version(lua, &self.id, &version)
+    }
+
     pub fn save_config(&self, lua: &Lua, table: LuaValue) -> LuaResult<()> {
-        let json = lua_to_json(table)?;
+        let mut config = to_json_value(&table, &SerializeOptions::config())
+            .map_err(|e| LuaError::RuntimeError(format!("Error converting config: {}", e)))?;
         let love_dir = get_love_dir(lua)?;
         let mods_dir = format!("{}/mods", love_dir);
         let mod_dir = format!("{}/{}", mods_dir, self.id);
-        let config_file = format!("{}/config.json", mod_dir);
-        std::fs::write(config_file, json)?;
+        let config_file = config_path(&mod_dir);
+        if let Some(schema) = self.read_config_schema(&mod_dir)? {
+            if let Some(defaults) = schema_defaults(&schema) {
+                merge_defaults(&mut config, &defaults);
+            }
+            self.validate_config(&schema, &config)?;
+        }
+        write_atomic(&config_file, config_text(&config_file, &config)?)?;
+        write_atomic(format!("{}/config.version", mod_dir), &self.version)?;
         Ok(())
     }
 
     pub fn load_config<'lua>(&self, lua: &'lua Lua) -> LuaResult<LuaValue<'lua>> {
+        match self.read_config(lua)? {
+            Some(config) => json_to_lua(lua, config.to_string()),
+            None => Ok(LuaValue::Nil),
+        }
+    }
+
+    pub fn config_ui<'lua>(&self, lua: &'lua Lua) -> LuaResult<LuaValue<'lua>> {
+        let love_dir = get_love_dir(lua)?;
+        let mods_dir = format!("{}/mods", love_dir);
+        let mod_dir = format!("{}/{}", mods_dir, self.id);
+        let schema = match self.read_config_schema(&mod_dir)? {
+            Some(schema) => schema,
+            None => return Ok(LuaValue::Nil),
+        };
+        let config = self.read_config(lua)?.unwrap_or(JsonValue::Null);
+        config_fields(&schema, &config).into_lua(lua)
+    }
+
+    pub fn set_config_value(&self, lua: &Lua, path: String, value: LuaValue) -> LuaResult<()> {
+        if path.is_empty() || path.split('.').any(|key| key.is_empty()) {
+            return Err(LuaError::RuntimeError(format!(
+                "Invalid config path: {}",
+                path
+            )));
+        }
         let love_dir = get_love_dir(lua)?;
         let mods_dir = format!("{}/mods", love_dir);
         let mod_dir = format!("{}/{}", mods_dir, self.id);
-        let config_file = format!("{}/config.json", mod_dir);
-        if !std::path::Path::new(&config_file).exists() {
+        let mut config = match self.read_config(lua)? {
+            Some(config) => config,
+            None => JsonValue::Object(Default::default()),
+        };
+        let value = lua_value_to_json_value(&value).map_err(|e| {
+            LuaError::RuntimeError(format!("Invalid value for config {}: {}", path, e))
+        })?;
+        set_path(&mut config, &path, value);
+        if let Some(schema) = self.read_config_schema(&mod_dir)? {
+            self.validate_config(&schema, &config)?;
+        }
+        let config_file = config_path(&mod_dir);
+        write_atomic(&config_file, config_text(&config_file, &config)?)?;
+        write_atomic(format!("{}/config.version", mod_dir), &self.version)?;
+        Ok(())
+    }
+
+    fn read_config(&self, lua: &Lua) -> LuaResult<Option<JsonValue>> {
+        let love_dir = get_love_dir(lua)?;
+        let mods_dir = format!("{}/mods", love_dir);
+        let mod_dir = format!("{}/{}", mods_dir, self.id);
+        let config_file = config_path(&mod_dir);
+        let config = read_with_backup(&config_file, |text| parse_config(&config_file, text))?;
+        let config_exists = config.is_some();
+        let mut config = match config {
+            Some(config) => config.map_err(|e| {
+                LuaError::RuntimeError(format!("Error parsing config for mod {}: {}", self.id, e))
+            })?,
+            None => JsonValue::Null,
+        };
+
+        let schema = match self.read_config_schema(&mod_dir)? {
+            Some(schema) => schema,
+            None => {
+                if !config_exists {
+                    println!("No config file found for mod: {}", self.id);
+                    return Ok(None);
+                }
+                return Ok(Some(config));
+            }
+        };
+        let version_file = format!("{}/config.version", mod_dir);
+        let config_version = if std::path::Path::new(&version_file).exists() {
+            std::fs::read_to_string(&version_file)?.trim().to_string()
+        } else {
+            UNVERSIONED.to_string()
+        };
+        let mut migrated = false;
+        if config_version != self.version && !config.is_null() {
+            let original = config.clone();
+            let migrations = self.read_migrations(&mod_dir)?;
+            let applied = migrate(&mut config, &migrations, &config_version, &self.version);
+            if !applied.is_empty() {
+                println!(
+                    "Migrated config for mod {} from {} to {} ({})",
+                    self.id,
+                    config_version,
+                    self.version,
+                    applied.join(", ")
+                );
+            }
+            migrated = config != original;
+        }
+
+        if let Some(defaults) = schema_defaults(&schema) {
+            merge_defaults(&mut config, &defaults);
+        }
+        if config.is_null() {
             println!("No config file found for mod: {}", self.id);
-            return Ok(LuaValue::Nil);
+            return Ok(None);
+        }
+        self.validate_config(&schema, &config)?;
+
+        // loading only writes the file back when a migration changed it
+        if migrated {
+            write_atomic(&config_file, config_text(&config_file, &config)?)?;
+            write_atomic(&version_file, &self.version)?;
+        }
+
+        Ok(Some(config))
+    }
+
+    fn read_config_schema(&self, mod_dir: &str) -> LuaResult<Option<JsonValue>> {
+        let schema_file = format!("{}/config.schema.json", mod_dir);
+        if !std::path::Path::new(&schema_file).exists() {
+            return Ok(None);
+        }
+        let schema = std::fs::read_to_string(schema_file)?;
+        let schema = serde_json::from_str(&schema).map_err(|e| {
+            LuaError::RuntimeError(format!(
+                "Error parsing config schema for mod {}: {}",
+                self.id, e
+            ))
+        })?;
+        Ok(Some(schema))
+    }
+
+    fn read_migrations(&self, mod_dir: &str) -> LuaResult<Vec<Migration>> {
+        let migrations_file = format!("{}/config.migrations.json", mod_dir);
+        if !std::path::Path::new(&migrations_file).exists() {
+            return Ok(Vec::new());
         }
+        let schema = include_bytes!("../schema/migrations.schema.json");
+        let schema = String::from_utf8(schema.to_vec()).unwrap();
+        let migrations = std::fs::read_to_string(migrations_file)?;
+        let validation = validate_schema(schema, migrations.clone());
+        if validation != "valid" {
+            return Err(LuaError::RuntimeError(format!(
+                "Invalid config migrations for mod {}: {}",
+                self.id, validation
+            )));
+        }
+        serde_json::from_str(&migrations).map_err(|e| {
+            LuaError::RuntimeError(format!(
+                "Error parsing config migrations for mod {}: {}",
+                self.id, e
+            ))
+        })
+    }
+
+    fn validate_config(&self, schema: &JsonValue, config: &JsonValue) -> LuaResult<()> {
+        let validation = validate_schema(schema.to_string(), config.to_string());
+        if validation != "valid" {
+            return Err(LuaError::RuntimeError(format!(
+                "Invalid config for mod {}: {}",
+                self.id, validation
+            )));
+        }
+        Ok(())
+    }
+}
+
+// config.toml when the mod ships one (or a backup of it is left), config.json otherwise
+fn config_path(mod_dir: &str) -> String {
+    let toml_file = format!("{}/config.toml", mod_dir);
+    let toml_path = std::path::Path::new(&toml_file);
+    if toml_path.exists() || backup_path(toml_path).exists() {
+        return toml_file;
+    }
+    format!("{}/config.json", mod_dir)
+}
+
+fn is_toml(config_file: &str) -> bool {
+    config_file.ends_with(".toml")
+}
+
+fn parse_config(config_file: &str, text: &str) -> Result<JsonValue, String> {
+    if is_toml(config_file) {
+        return parse_toml(text);
+    }
+    serde_json::from_str(text).map_err(|e| e.to_string())
+}
 
-        let json = std::fs::read_to_string(config_file)?;
-        json_to_lua(lua, json)
+fn config_text(config_file: &str, config: &JsonValue) -> LuaResult<String> {
+    let options = SerializeOptions::config();
+    if is_toml(config_file) {
+        write_toml(config, &options)
+    } else {
+        write_json(config, &options)
     }
+    .map_err(|e| LuaError::RuntimeError(format!("Error writing config: {}", e)))
 }
@@ -1,2 +1,12 @@
+pub mod commandentry;
+pub mod configfield;
+pub mod functionchange;
+pub mod gameversion;
+pub mod localizationconflict;
 pub mod localmod;
+pub mod modevent;
 pub mod modinfo;
+pub mod modupdate;
+pub mod overlayentry;
+pub mod patchrecord;
+pub mod serializeoptions;
@@ -0,0 +1,38 @@
+use mlua::prelude::{LuaResult, LuaValue};
+use mlua::{IntoLua, Lua};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModEventKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl ModEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModEventKind::Added => "added",
+            ModEventKind::Removed => "removed",
+            ModEventKind::Changed => "changed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModEvent {
+    pub kind: ModEventKind,
+    pub mod_id: String,
+    // path relative to the mod folder, None when the whole mod was added or removed
+    pub path: Option<String>,
+}
+
+impl IntoLua<'_> for ModEvent {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("kind", self.kind.as_str())?;
+        table.set("mod_id", self.mod_id)?;
+        table.set("is_mod", self.path.is_none())?;
+        table.set("path", self.path)?;
+        Ok(LuaValue::Table(table))
+    }
+}
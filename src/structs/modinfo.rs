@@ -10,6 +10,7 @@ pub struct ModInfo {
     pub description: Vec<String>,
     pub version: String,
     pub authors: Vec<String>,
+    pub changelog: Option<String>,
 }
 
 impl IntoLua<'_> for ModInfo {
@@ -23,6 +24,7 @@ impl IntoLua<'_> for ModInfo {
         table.set("description", self.description)?;
         table.set("version", self.version)?;
         table.set("authors", self.authors)?;
+        table.set("changelog", self.changelog)?;
         table.set("download", download_func)?;
         Ok(LuaValue::Table(table))
     }
@@ -38,6 +40,7 @@ impl FromLua<'_> for ModInfo {
             description: table.get("description")?,
             version: table.get("version")?,
             authors: table.get("authors")?,
+            changelog: table.get("changelog")?,
         })
     }
 }
@@ -0,0 +1,71 @@
+use crate::structs::modinfo::ModInfo;
+use mlua::prelude::{LuaResult, LuaValue};
+use mlua::{IntoLua, Lua, Table};
+
+#[derive(Debug, Clone)]
+pub struct ModUpdate {
+    pub id: String,
+    pub name: String,
+    pub current_version: String,
+    pub new_version: String,
+    pub changelog: Option<String>,
+    pub mod_info: ModInfo,
+}
+
+impl IntoLua<'_> for ModUpdate {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        Ok(LuaValue::Table(self.into_table(lua)?))
+    }
+}
+
+// The outcome of applying one update in update_all, the update's fields with `ok` and `error`
+#[derive(Debug, Clone)]
+pub struct ModUpdateResult {
+    pub update: ModUpdate,
+    pub error: Option<String>,
+}
+
+impl IntoLua<'_> for ModUpdateResult {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = self.update.into_table(lua)?;
+        table.set("ok", self.error.is_none())?;
+        table.set("error", self.error)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl ModUpdate {
+    fn into_table(self, lua: &Lua) -> LuaResult<Table> {
+        let table = lua.create_table()?;
+        let apply_update = self.clone();
+        let ignore_update = self.clone();
+        table.set(
+            "apply",
+            lua.create_function(move |lua, ()| apply_update.apply(lua))?,
+        )?;
+        table.set(
+            "ignore",
+            lua.create_function(move |lua, ()| ignore_update.ignore(lua))?,
+        )?;
+        table.set("id", self.id)?;
+        table.set("name", self.name)?;
+        table.set("current_version", self.current_version)?;
+        table.set("new_version", self.new_version)?;
+        table.set("changelog", self.changelog)?;
+        table.set("mod_info", self.mod_info)?;
+        Ok(table)
+    }
+
+    pub fn apply(&self, lua: &Lua) -> LuaResult<()> {
+        self.mod_info.download(lua)?;
+        println!(
+            "Updated mod {} from {} to {}",
+            self.id, self.current_version, self.new_version
+        );
+        Ok(())
+    }
+
+    pub fn ignore(&self, lua: &Lua) -> LuaResult<()> {
+        crate::mods::ignore_mod_version(lua, &self.id, &self.new_version)
+    }
+}
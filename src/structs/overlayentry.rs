@@ -0,0 +1,24 @@
+use mlua::prelude::{LuaResult, LuaValue};
+use mlua::{IntoLua, Lua};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlayEntry {
+    // game path the file stands in for, e.g. `resources/textures/1x/Jokers.png`
+    pub path: String,
+    pub mod_id: String,
+    // the mod's file, relative to the save directory so love.filesystem can open it
+    pub file: String,
+    // mods that registered the same path earlier and were overridden
+    pub overridden: Vec<String>,
+}
+
+impl IntoLua<'_> for OverlayEntry {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("path", self.path)?;
+        table.set("mod_id", self.mod_id)?;
+        table.set("file", self.file)?;
+        table.set("overridden", self.overridden)?;
+        Ok(LuaValue::Table(table))
+    }
+}
@@ -0,0 +1,107 @@
+use crate::lua::lexer::Span;
+use crate::lua::patch::{function_edits, text_edits, AstTarget, Edit, TextTarget};
+use mlua::prelude::{LuaResult, LuaValue};
+use mlua::{IntoLua, Lua};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchKind {
+    Text { find: String, target: TextTarget },
+    Ast(AstTarget),
+}
+
+impl PatchKind {
+    // Edits inserting `insert` into the current `code` of the function
+    pub fn edits(&self, code: &str, insert: &str) -> Result<Vec<Edit>, String> {
+        match self {
+            PatchKind::Text { find, target } => text_edits(code, find, insert, target),
+            PatchKind::Ast(target) => function_edits(code, target, insert),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchRecord {
+    // position in the order patches were applied, shared by every function
+    pub order: usize,
+    pub mod_id: String,
+    pub file: String,
+    pub function: String,
+    pub kind: PatchKind,
+    pub insert: String,
+    pub count: usize,
+    // byte ranges of the current function source written by this patch
+    pub regions: Vec<Span>,
+}
+
+impl IntoLua<'_> for PatchRecord {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("order", self.order)?;
+        table.set("mod_id", self.mod_id)?;
+        table.set("file", self.file)?;
+        table.set("function", self.function)?;
+        match self.kind {
+            PatchKind::Text { find, .. } => {
+                table.set("kind", "text")?;
+                table.set("find", find)?;
+            }
+            PatchKind::Ast(target) => {
+                table.set("kind", "ast")?;
+                table.set("find", target.describe())?;
+            }
+        }
+        table.set("insert", self.insert)?;
+        table.set("count", self.count)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchConflict {
+    pub file: String,
+    pub function: String,
+    // the mod whose patch touched code already patched by `other_mod_id`
+    pub mod_id: String,
+    pub other_mod_id: String,
+    pub message: String,
+}
+
+impl IntoLua<'_> for PatchConflict {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("file", self.file)?;
+        table.set("function", self.function)?;
+        table.set("mod_id", self.mod_id)?;
+        table.set("other_mod_id", self.other_mod_id)?;
+        table.set("message", self.message)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+// Outcome of one entry of a mod's patches.json
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchResult {
+    pub mod_id: String,
+    // 1-based position in patches.json, 0 when the whole file could not be read
+    pub index: usize,
+    pub description: Option<String>,
+    pub file: Option<String>,
+    pub function: Option<String>,
+    pub count: usize,
+    pub error: Option<String>,
+}
+
+impl IntoLua<'_> for PatchResult {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("mod_id", self.mod_id)?;
+        table.set("index", self.index)?;
+        table.set("description", self.description)?;
+        table.set("file", self.file)?;
+        table.set("function", self.function)?;
+        table.set("count", self.count)?;
+        table.set("success", self.error.is_none())?;
+        table.set("error", self.error)?;
+        Ok(LuaValue::Table(table))
+    }
+}
@@ -0,0 +1,57 @@
+use mlua::prelude::{LuaError, LuaResult, LuaValue};
+use mlua::{FromLua, Lua};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SerializeOptions {
+    // tables nested more than this many levels below the value are written {"$truncated": true}
+    pub max_depth: Option<usize>,
+    // a table met again is written {"$ref": id}, the first occurrence of such a table gets
+    // an "$id". Without it, a table containing itself is an error.
+    pub refs: bool,
+    // functions, userdata and keys JSON can't hold are left out instead of failing
+    pub skip_unsupported: bool,
+    // spaces per level of a pretty-printed output, on a single line when None
+    pub indent: Option<usize>,
+    pub sort_keys: bool,
+    // escape every non-ASCII character as \uXXXX
+    pub ascii: bool,
+    // significant digits floats are rounded to, e.g. 14 writes 0.1 + 0.2 as 0.3
+    pub precision: Option<usize>,
+}
+
+impl SerializeOptions {
+    // How config files are written: indented and with sorted keys, so that players can edit
+    // them and their diffs stay small
+    pub fn config() -> SerializeOptions {
+        SerializeOptions {
+            indent: Some(2),
+            sort_keys: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl FromLua<'_> for SerializeOptions {
+    fn from_lua(value: LuaValue, _: &'_ Lua) -> LuaResult<Self> {
+        let table = match value {
+            LuaValue::Nil => return Ok(SerializeOptions::default()),
+            LuaValue::Table(table) => table,
+            _ => {
+                return Err(LuaError::RuntimeError(
+                    "Serialization options must be a table".to_string(),
+                ))
+            }
+        };
+        Ok(SerializeOptions {
+            max_depth: table.get("max_depth")?,
+            refs: table.get::<_, Option<bool>>("refs")?.unwrap_or(false),
+            skip_unsupported: table
+                .get::<_, Option<bool>>("skip_unsupported")?
+                .unwrap_or(false),
+            indent: table.get("indent")?,
+            sort_keys: table.get::<_, Option<bool>>("sort_keys")?.unwrap_or(false),
+            ascii: table.get::<_, Option<bool>>("ascii")?.unwrap_or(false),
+            precision: table.get("precision")?,
+        })
+    }
+}
@@ -1,9 +1,65 @@
 #[cfg(test)]
 mod tests {
+    use crate::commands::{
+        command_line_words, parse_args, parse_usage, split_command_line, CommandRegistry,
+    };
+    use crate::config::{
+        config_fields, merge_defaults, migrate, schema_defaults, Migration, UNVERSIONED,
+    };
+    use crate::diff::{diff_lines, diff_sources, unified_diff};
+    use crate::formats::{
+        json_value_to_toml, parse_msgpack, parse_toml, parse_yaml, write_msgpack, write_toml,
+        write_yaml,
+    };
+    use crate::localization::{locale_file, LocaleMerge};
+    use crate::lua::functions::find_functions;
+    use crate::lua::lexer::{tokenize, TokenKind};
+    use crate::lua::patch::{
+        apply_edits, edited_regions, excerpt, function_edits, text_edits, Anchor, AstTarget,
+        Position, TextMode, TextTarget,
+    };
+    use crate::overlay::{normalize_path, scan_overlay_dir, Overlay};
+    use crate::patches::{parse_patch_file, PatchRegistry};
+    use crate::persistence::{backup_path, read_with_backup, write_atomic};
+    use crate::serialization::{
+        array_length, decode_bytes, encode_bytes, escape_non_ascii, path_segment,
+        round_significant, sorted_keys, special_number, write_json, JsonKey,
+    };
+    use crate::sources::{
+        build_source_index, fingerprint_sources, hash_game_sources, read_lua_sources,
+    };
+    use crate::structs::functionchange::ChangeKind;
+    use crate::structs::gameversion::GameVersion;
+    use crate::structs::localmod::ModCommand;
+    use crate::structs::modevent::ModEventKind;
+    use crate::structs::patchrecord::PatchKind;
+    use crate::structs::serializeoptions::SerializeOptions;
     use crate::updater::get_latest_cli_version;
-    use crate::utils::minify_lua;
+    use crate::utils::{is_newer_version, minify_lua};
+    use crate::watcher::{diff_snapshots, scan_mods_dir};
+    use serde_json::json;
+    use std::collections::HashMap;
     use std::fs;
 
+    fn patch_function(
+        code: &str,
+        target: &AstTarget,
+        insert: &str,
+    ) -> Result<(String, usize), String> {
+        let edits = function_edits(code, target, insert)?;
+        Ok((apply_edits(code, &edits), edits.len()))
+    }
+
+    fn patch_text(
+        code: &str,
+        find: &str,
+        insert: &str,
+        target: &TextTarget,
+    ) -> Result<(String, usize), String> {
+        let edits = text_edits(code, find, insert, target)?;
+        Ok((apply_edits(code, &edits), edits.len()))
+    }
+
     #[test]
     fn test_update() {
         let version = String::from("v0.1.10");
@@ -28,6 +84,725 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_extract_functions() {
+        let code = r#"
+local counter = 0
+local function helper(x)
+    if x then
+        return x
+end
+end
+
+function Card:calculate_joker(context)
+    local s = "end"
+    if context.joker_main then
+        helper(function() end)
+    end
+end
+
+G.FUNCS.play_cards = function(e)
+    counter = counter + 1
+end
+G.FUNCS["can_play"] = function(e) end
+
+    function indented.fn()
+    end
+
+SMODS = {
+    Joker = {
+        inject = function(self) return [[
+end]] end,
+    },
+}
+"#;
+        let functions = find_functions(code).unwrap();
+        let names: Vec<&str> = functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "helper",
+                "Card:calculate_joker",
+                "G.FUNCS.play_cards",
+                "G.FUNCS.can_play",
+                "indented.fn",
+                "SMODS.Joker.inject"
+            ]
+        );
+        assert!(functions[0].is_local);
+        assert_eq!(functions[1].start_line, 9);
+        assert_eq!(functions[1].end_line, 14);
+        assert!(functions[1]
+            .code
+            .ends_with("helper(function() end)\n    end\nend"));
+        assert_eq!(
+            functions[5].code,
+            "SMODS.Joker.inject = function(self) return [[\nend]] end"
+        );
+
+        let extracted = crate::utils::extract_functions(code.to_string());
+        assert_eq!(
+            extracted["G.FUNCS.can_play"],
+            r#"G.FUNCS["can_play"] = function(e) end"#
+        );
+        assert!(!extracted.contains_key("helper"));
+
+        // the fields of a local table are local too, and are set through the table
+        let code = "local Util = { fmt = function(x) end }\nlocal f = function() end";
+        let functions = find_functions(code).unwrap();
+        assert_eq!(functions[0].name, "Util.fmt");
+        assert!(functions[0].is_local);
+        assert_eq!(functions[0].code, "Util.fmt = function(x) end");
+        assert_eq!(functions[1].code, "local f = function() end");
+        assert!(crate::utils::extract_functions(code.to_string()).is_empty());
+    }
+
+    #[test]
+    fn test_patch_function_by_ast() {
+        let code = minify_lua(
+            r#"function Card:calculate_joker(context)
+    if context.joker_main then
+        ease_dollars(self.ability.extra)
+        G.E_MANAGER:add_event(Event({func = function()
+            ease_dollars(1)
+            return true
+        end}))
+        return {message = "ok"}
+    end
+end"#
+                .to_string(),
+        );
+        let target = |anchor, position, nth| AstTarget {
+            anchor,
+            position,
+            nth,
+        };
+
+        let (patched, count) = patch_function(
+            &code,
+            &target(
+                Anchor::Call("ease_dollars".to_string()),
+                Position::After,
+                None,
+            ),
+            "print(1)",
+        )
+        .unwrap();
+        assert_eq!(count, 2);
+        assert!(patched.contains("ease_dollars(self.ability.extra) print(1) G.E_MANAGER"));
+        assert!(patched.contains("ease_dollars(1) print(1) return true"));
+
+        let (patched, count) = patch_function(
+            &code,
+            &target(Anchor::Return, Position::Before, Some(1)),
+            "x = 1",
+        )
+        .unwrap();
+        assert_eq!(count, 1);
+        assert!(patched.contains(r#"x = 1 return {message = "ok"}"#));
+        assert!(!patched.contains("x = 1 return true"));
+
+        let (patched, _) =
+            patch_function(&code, &target(Anchor::Start, Position::Before, None), "a()").unwrap();
+        assert!(patched.starts_with("function Card:calculate_joker(context) a() if"));
+        let (patched, _) =
+            patch_function(&code, &target(Anchor::End, Position::Before, None), "b()").unwrap();
+        assert!(patched.ends_with(r#"{message = "ok"} end b() end"#));
+
+        let (patched, _) = patch_function(
+            &code,
+            &target(
+                Anchor::Call("G.E_MANAGER:add_event".to_string()),
+                Position::Replace,
+                None,
+            ),
+            "nil",
+        )
+        .unwrap();
+        assert!(patched.contains("ease_dollars(self.ability.extra) nil return"));
+
+        let (patched, count) = patch_function(
+            "function f(x) return g(g(x), g(1)) + g(2) end",
+            &target(Anchor::Call("g".to_string()), Position::Replace, None),
+            "h()",
+        )
+        .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(patched, "function f(x) return h() + h() end");
+
+        assert!(patch_function(
+            &code,
+            &target(
+                Anchor::Call("ease_chips".to_string()),
+                Position::Before,
+                None
+            ),
+            "x()"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_patch_text_modes() {
+        let code = "function f() ease_dollars(1) x = 1 ease_dollars(2) end";
+        let target = |mode, occurrence, regex| TextTarget {
+            mode,
+            occurrence,
+            regex,
+        };
+
+        let (patched, count) =
+            patch_text(code, "ease_dollars", "ease_chips", &TextTarget::default()).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            patched,
+            "function f() ease_chips(1) x = 1 ease_chips(2) end"
+        );
+
+        let (patched, count) = patch_text(
+            code,
+            "x = 1",
+            "y = 2 ",
+            &target(TextMode::Before, Some(1), false),
+        )
+        .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(
+            patched,
+            "function f() ease_dollars(1) y = 2 x = 1 ease_dollars(2) end"
+        );
+
+        let (patched, _) = patch_text(
+            code,
+            "ease_dollars(2)",
+            " print(2)",
+            &target(TextMode::After, None, false),
+        )
+        .unwrap();
+        assert!(patched.ends_with("ease_dollars(2) print(2) end"));
+
+        let (patched, count) = patch_text(
+            code,
+            r"ease_dollars\((\d+)\)",
+            "ease_dollars($1 * 2)",
+            &target(TextMode::Replace, Some(2), true),
+        )
+        .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(
+            patched,
+            "function f() ease_dollars(1) x = 1 ease_dollars(2 * 2) end"
+        );
+
+        let error = patch_text(code, "ease_dolars(1)", "", &TextTarget::default()).unwrap_err();
+        assert!(error.contains("closest match"));
+        assert!(error.contains("ease_dollars(1)"));
+        assert!(patch_text(
+            code,
+            "x = 1",
+            "",
+            &target(TextMode::Replace, Some(2), false)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_patch_excerpt() {
+        let code = "function f() ease_dollars(1) x = 1 ease_dollars(2) end";
+        let target = TextTarget {
+            mode: TextMode::Before,
+            occurrence: None,
+            regex: false,
+        };
+        let edits = text_edits(code, "ease_dollars", "if ", &target).unwrap();
+        let patched = apply_edits(code, &edits);
+        let regions = edited_regions(&edits);
+        assert_eq!(regions.len(), 2);
+        for region in regions.iter() {
+            assert_eq!(&patched[region.start..region.end], "if ");
+        }
+        assert_eq!(
+            excerpt(&patched, regions[1], 6),
+            "...x = 1 >>>if <<<ease_d..."
+        );
+        assert_eq!(
+            excerpt(&patched, regions[0], 100),
+            format!("{}>>>if <<<{}", &patched[..13], &patched[16..])
+        );
+    }
+
+    #[test]
+    fn test_patch_registry_conflicts() {
+        let mut registry = PatchRegistry::default();
+        let mut code = "function f() ease_dollars(1) x = 1 end".to_string();
+        let mut patch = |registry: &mut PatchRegistry, mod_id: &str, find: &str, insert: &str| {
+            let target = TextTarget::default();
+            let edits = text_edits(&code, find, insert, &target)?;
+            code = apply_edits(&code, &edits);
+            let kind = PatchKind::Text {
+                find: find.to_string(),
+                target,
+            };
+            Ok::<_, String>(registry.record(mod_id, "card.lua", "f", kind, insert, &edits, None))
+        };
+
+        let conflicts = patch(&mut registry, "a", "ease_dollars(1)", "ease_dollars(5)").unwrap();
+        assert!(conflicts.is_empty());
+        // code next to the first patch is not a conflict
+        let conflicts = patch(&mut registry, "b", "x = 1", "x = 2").unwrap();
+        assert!(conflicts.is_empty());
+        // rewriting what mod a wrote is
+        let conflicts = patch(&mut registry, "c", "dollars(5)", "dollars(10)").unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].mod_id, "c");
+        assert_eq!(conflicts[0].other_mod_id, "a");
+        assert!(patch(&mut registry, "c", "dollars(10)", "dollars(20)")
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(registry.patches.len(), 4);
+        assert_eq!(registry.patches[1].regions.len(), 1);
+        let region = registry.patches[1].regions[0];
+        assert_eq!(&code[region.start..region.end], "x = 2");
+
+        let failures = registry.record_failure("d", "card.lua", "f", "Code not found");
+        assert_eq!(failures.len(), 3);
+        assert_eq!(registry.patched_by("card.lua", "f", "c"), vec!["a", "b"]);
+        assert_eq!(registry.conflicts.len(), 4);
+    }
+
+    #[test]
+    fn test_patch_registry_revert() {
+        let original = "function f() ease_dollars(1) x = 1 end";
+        let mut registry = PatchRegistry::default();
+        let mut code = original.to_string();
+        for (mod_id, find, insert) in [
+            ("a", "ease_dollars(1)", "ease_dollars(5)"),
+            ("b", "x = 1", "x = 2"),
+            ("a", "x = 2", "x = 2 y = 3"),
+        ] {
+            let kind = PatchKind::Text {
+                find: find.to_string(),
+                target: TextTarget::default(),
+            };
+            let edits = kind.edits(&code, insert).unwrap();
+            registry.keep_original("card.lua", "f", &code);
+            code = apply_edits(&code, &edits);
+            registry.record(mod_id, "card.lua", "f", kind, insert, &edits, None);
+        }
+        assert_eq!(code, "function f() ease_dollars(5) x = 2 y = 3 end");
+        assert_eq!(
+            registry.functions_patched_by("a"),
+            vec![("card.lua".to_string(), "f".to_string())]
+        );
+
+        // what revert_mod("a") does: restore the original and replay the patches of b
+        let (restored, patches) = registry.forget("card.lua", "f");
+        assert_eq!(restored.as_deref(), Some(original));
+        assert_eq!(patches.len(), 3);
+        assert!(registry.patches.is_empty());
+        let mut code = original.to_string();
+        for patch in patches.into_iter().filter(|patch| patch.mod_id != "a") {
+            let edits = patch.kind.edits(&code, &patch.insert).unwrap();
+            code = apply_edits(&code, &edits);
+            let (mod_id, order) = (patch.mod_id.clone(), Some(patch.order));
+            registry.record(
+                &mod_id,
+                "card.lua",
+                "f",
+                patch.kind,
+                &patch.insert,
+                &edits,
+                order,
+            );
+        }
+        assert_eq!(code, "function f() ease_dollars(1) x = 2 end");
+        assert_eq!(registry.patches.len(), 1);
+        assert_eq!(registry.patches[0].order, 1);
+    }
+
+    #[test]
+    fn test_patch_file_entries() {
+        let patches = json!([
+            {
+                "description": "double the money",
+                "file": "functions/common_events.lua",
+                "function": "ease_dollars",
+                "find": "mod",
+                "mode": "after",
+                "occurrence": 1,
+                "payload": " * 2",
+                "game_versions": ["1.0.1"]
+            },
+            {
+                "file": "card.lua",
+                "function": "Card:calculate_joker",
+                "at": "call",
+                "name": "ease_dollars",
+                "position": "replace",
+                "payload": "ease_chips(1)"
+            }
+        ]);
+        let entries = parse_patch_file(&patches.to_string()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].game_versions, vec!["1.0.1"]);
+        assert_eq!(
+            entries[0].kind().unwrap(),
+            PatchKind::Text {
+                find: "mod".to_string(),
+                target: TextTarget {
+                    mode: TextMode::After,
+                    occurrence: Some(1),
+                    regex: false,
+                },
+            }
+        );
+        assert_eq!(
+            entries[1].kind().unwrap(),
+            PatchKind::Ast(AstTarget {
+                anchor: Anchor::Call("ease_dollars".to_string()),
+                position: Position::Replace,
+                nth: None,
+            })
+        );
+
+        // a patch needs exactly one of find and at, and a call anchor needs a name
+        let invalid = [
+            json!([{"file": "card.lua", "function": "f", "payload": "x()"}]),
+            json!([{"file": "card.lua", "function": "f", "find": "a", "at": "end", "payload": ""}]),
+            json!([{"file": "card.lua", "function": "f", "at": "call", "payload": "x()"}]),
+            json!([{"file": "card.lua", "function": "f", "find": "a", "mode": "x", "payload": ""}]),
+        ];
+        for patches in invalid.iter() {
+            assert!(parse_patch_file(&patches.to_string()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_version_comparison() {
+        assert!(is_newer_version("1.0.0", "1.0.1"));
+        assert!(is_newer_version("1.0.0", "v1.2.0"));
+        assert!(!is_newer_version("1.2.0", "1.2.0"));
+        assert!(!is_newer_version("1.10.0", "1.9.0"));
+        assert!(is_newer_version("1.0", "1.2"));
+        assert!(is_newer_version("1", "1.0.1"));
+        assert!(!is_newer_version("1.2", "1.2.0"));
+        assert!(is_newer_version("1.2-beta", "1.2"));
+        // not semver, compared as text
+        assert!(is_newer_version("1.0.0", "latest"));
+        assert!(!is_newer_version("latest", "latest"));
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "volume": {"type": "number", "default": 0.5},
+                "keys": {
+                    "type": "object",
+                    "properties": {"pause": {"type": "string", "default": "p"}}
+                },
+                "name": {"type": "string"}
+            }
+        });
+        let defaults = schema_defaults(&schema).unwrap();
+        assert_eq!(defaults, json!({"volume": 0.5, "keys": {"pause": "p"}}));
+
+        let mut config = json!({"volume": 1.0, "keys": {}});
+        merge_defaults(&mut config, &defaults);
+        assert_eq!(config, json!({"volume": 1.0, "keys": {"pause": "p"}}));
+    }
+
+    #[test]
+    fn test_config_migrations() {
+        let migrations: Vec<Migration> = serde_json::from_value(json!([
+            {"version": "1.2.0", "set": {"speed": 2}},
+            {"version": "1.1.0", "rename": {"volume": "audio.volume"}, "remove": ["legacy"]},
+            {"version": "2.0.0", "set": {"speed": 3}}
+        ]))
+        .unwrap();
+        let mut config = json!({"volume": 0.8, "legacy": true});
+        let applied = migrate(&mut config, &migrations, "1.0.0", "1.2.0");
+        assert_eq!(applied, vec!["1.1.0", "1.2.0"]);
+        assert_eq!(config, json!({"audio": {"volume": 0.8}, "speed": 2}));
+
+        // a config without a version goes through every migration
+        let mut config = json!({"volume": 0.8, "legacy": true});
+        let applied = migrate(&mut config, &migrations, UNVERSIONED, "2.0.0");
+        assert_eq!(applied, vec!["1.1.0", "1.2.0", "2.0.0"]);
+        assert_eq!(config, json!({"audio": {"volume": 0.8}, "speed": 3}));
+    }
+
+    #[test]
+    fn test_config_fields() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "music_volume": {"type": "number", "minimum": 0, "maximum": 1, "default": 0.5},
+                "difficulty": {"title": "Difficulty", "enum": ["easy", "hard"], "default": "easy"},
+                "keys": {
+                    "type": "object",
+                    "properties": {"pause": {"type": "string"}}
+                },
+                "speed": {"type": "number", "exclusiveMinimum": 0, "maximum": 4, "exclusiveMaximum": true}
+            }
+        });
+        let fields = config_fields(
+            &schema,
+            &json!({"music_volume": 0.2, "keys": {"pause": "p"}}),
+        );
+        assert_eq!(fields.len(), 4);
+        assert_eq!(fields[0].label, "Music volume");
+        assert_eq!(fields[0].field_type, "number");
+        assert_eq!(fields[0].maximum, Some(1.0));
+        assert!(!fields[0].exclusive_maximum);
+        assert_eq!(fields[0].value, Some(json!(0.2)));
+        assert_eq!(fields[1].field_type, "enum");
+        assert_eq!(fields[1].options, Some(vec![json!("easy"), json!("hard")]));
+        assert_eq!(fields[1].value, None);
+        assert_eq!(fields[2].fields[0].path, "keys.pause");
+        assert_eq!(fields[2].fields[0].value, Some(json!("p")));
+        assert_eq!(fields[3].minimum, Some(0.0));
+        assert!(fields[3].exclusive_minimum);
+        assert_eq!(fields[3].maximum, Some(4.0));
+        assert!(fields[3].exclusive_maximum);
+    }
+
+    #[test]
+    fn test_atomic_write_backup() {
+        let dir = std::env::temp_dir().join("balalib_test_atomic_write");
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.json");
+        write_atomic(&file, r#"{"a":1}"#).unwrap();
+        write_atomic(&file, r#"{"a":2}"#).unwrap();
+        assert_eq!(
+            fs::read_to_string(backup_path(&file)).unwrap(),
+            r#"{"a":1}"#
+        );
+
+        // a torn write falls back to the previous generation
+        fs::write(&file, r#"{"a":"#).unwrap();
+        let config = read_with_backup(&file, |json| {
+            serde_json::from_str::<serde_json::Value>(json)
+        })
+        .unwrap()
+        .unwrap()
+        .unwrap();
+        assert_eq!(config, json!({"a": 1}));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mod_watcher_diff() {
+        let dir = std::env::temp_dir().join("balalib_test_mod_watcher");
+        fs::create_dir_all(dir.join("foo")).unwrap();
+        fs::write(dir.join("foo/main.lua"), "print('foo')").unwrap();
+        let before = scan_mods_dir(&dir);
+
+        fs::write(dir.join("foo/main.lua"), "print('foo changed')").unwrap();
+        fs::write(dir.join("foo/main.lua.tmp"), "").unwrap();
+        fs::create_dir_all(dir.join("bar")).unwrap();
+        let after = scan_mods_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let events = diff_snapshots(&before, &after);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].mod_id, "bar");
+        assert_eq!(events[0].kind, ModEventKind::Added);
+        assert_eq!(events[0].path, None);
+        assert_eq!(events[1].kind, ModEventKind::Changed);
+        assert_eq!(events[1].path.as_deref(), Some("main.lua"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mod_watcher_symlink_loop() {
+        let dir = std::env::temp_dir().join("balalib_test_mod_watcher_link");
+        fs::create_dir_all(dir.join("foo/src")).unwrap();
+        fs::write(dir.join("foo/src/main.lua"), "print('foo')").unwrap();
+        std::os::unix::fs::symlink(dir.join("foo"), dir.join("foo/src/parent")).unwrap();
+        let snapshot = scan_mods_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let files: Vec<&String> = snapshot["foo"].keys().collect();
+        assert_eq!(files, vec!["src/main.lua", "src/parent"]);
+    }
+
+    #[test]
+    fn test_source_index_hash() {
+        let dir = std::env::temp_dir().join("balalib_test_source_hash");
+        fs::create_dir_all(dir.join("functions")).unwrap();
+        fs::write(dir.join("card.lua"), "function Card:init() end").unwrap();
+        fs::write(dir.join("functions/misc.lua"), "local x = 1").unwrap();
+        let hash = hash_game_sources(&dir).unwrap();
+        fs::write(dir.join("notes.txt"), "not a game file").unwrap();
+        assert_eq!(hash_game_sources(&dir).unwrap(), hash);
+        fs::write(dir.join("functions/misc.lua"), "local x = 2").unwrap();
+        let changed = hash_game_sources(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_ne!(changed, hash);
+
+        let lua_file = fs::read_to_string("test_minify.lua").unwrap();
+        let index = build_source_index([("card".to_string(), lua_file)].into());
+        assert_eq!(
+            index["card"].keys().collect::<Vec<_>>(),
+            vec!["Card:set_ability", "G.FUNCS.can_discard"]
+        );
+        assert!(!index["card"]["Card:set_ability"].contains("keep the old one around"));
+        let index = build_source_index(
+            [(
+                "misc".to_string(),
+                "function  f(a,b)  return a  +  b end".to_string(),
+            )]
+            .into(),
+        );
+        assert_eq!(index["misc"]["f"], "function f(a,b) return a + b end");
+    }
+
+    #[test]
+    fn test_read_lua_sources() {
+        let dir = std::env::temp_dir().join("balalib_test_read_sources");
+        fs::create_dir_all(dir.join("game/functions/ui")).unwrap();
+        fs::write(dir.join("game/card.lua"), "function Card:init() end").unwrap();
+        fs::write(dir.join("game/functions/ui/button.lua"), "local x = 1").unwrap();
+        fs::write(dir.join("game/resources.txt"), "not lua").unwrap();
+        let files = read_lua_sources(&dir.join("game")).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files["card"], "function Card:init() end");
+        assert_eq!(files["functions/ui/button"], "local x = 1");
+
+        // a .love file is a zip archive, fused executables have one appended
+        let love = dir.join("game.love");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&love).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("card.lua", options).unwrap();
+        std::io::Write::write_all(&mut zip, b"function Card:init() end").unwrap();
+        zip.start_file("resources/sounds.txt", options).unwrap();
+        zip.finish().unwrap();
+        let files = read_lua_sources(&love).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files["card"], "function Card:init() end");
+
+        let error = read_lua_sources(&dir.join("game/card.lua")).unwrap_err();
+        assert!(error.to_string().contains("not a game archive"));
+        assert!(read_lua_sources(&dir.join("missing.exe")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_game_version_fingerprint() {
+        let mut files: HashMap<String, String> = [
+            ("card".to_string(), "function Card:init() end".to_string()),
+            (
+                "globals".to_string(),
+                "VERSION = '1.0.1o'\nVERSION = VERSION..'-FULL'".to_string(),
+            ),
+        ]
+        .into();
+        let version = fingerprint_sources(&files);
+        assert_eq!(version.version.as_deref(), Some("1.0.1o"));
+        assert_eq!(fingerprint_sources(&files), version);
+        assert!(version.matches(&[]));
+        assert!(version.matches(&["1.0.1".to_string()]));
+        assert!(version.matches(&["1.0.0".to_string(), version.hash.clone()]));
+        assert!(!version.matches(&["1.0.0".to_string()]));
+        assert!(!version.matches(&["1.0.".to_string()]));
+        let ten = GameVersion {
+            version: Some("1.0.10".to_string()),
+            hash: version.hash.clone(),
+        };
+        assert!(!ten.matches(&["1.0.1".to_string()]));
+        assert!(ten.matches(&["1.0.10".to_string()]));
+        // FNV-1a, the same on every platform and Rust release
+        assert_eq!(
+            fingerprint_sources(&[("a".to_string(), String::new())].into()).hash,
+            "af63dc4c8601ec8c"
+        );
+
+        files.insert("card".to_string(), "function Card:init() end ".to_string());
+        let patched = fingerprint_sources(&files);
+        assert_eq!(patched.version, version.version);
+        assert_ne!(patched.hash, version.hash);
+        assert_eq!(patched.fingerprint(), format!("1.0.1o+{}", patched.hash));
+    }
+
+    #[test]
+    fn test_source_diff() {
+        let old = build_source_index(
+            [(
+                "card".to_string(),
+                "function Card:init() self.a = 1 end\nfunction Card:remove() end\n\
+                 function Card:calc() local x = 1 if x then return x end return 0 end"
+                    .to_string(),
+            )]
+            .into(),
+        );
+        let new = build_source_index(
+            [(
+                "card".to_string(),
+                "function Card:init() self.a = 1 end\nfunction Card:added() end\n\
+                 function Card:calc() local x = 2 if x then return x end return 0 end"
+                    .to_string(),
+            )]
+            .into(),
+        );
+        let targets = [
+            ("a".to_string(), "card".to_string(), "Card:calc".to_string()),
+            ("b".to_string(), "card".to_string(), "Card:init".to_string()),
+        ];
+        let changes = diff_sources(&old, &new, &targets);
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].function, "Card:added");
+        assert_eq!(changes[0].kind, ChangeKind::Added);
+        assert_eq!(changes[1].function, "Card:calc");
+        assert_eq!(changes[1].kind, ChangeKind::Changed);
+        assert_eq!(changes[1].patched_by, vec!["a"]);
+        assert_eq!(
+            changes[1].diff.as_deref(),
+            Some(
+                "--- a/card/Card:calc\n+++ b/card/Card:calc\n@@ -1,5 +1,5 @@\n \
+                 function Card:calc()\n-local x = 1\n+local x = 2\n if x then\n return x\n end\n"
+            )
+        );
+        assert_eq!(changes[2].function, "Card:remove");
+        assert_eq!(changes[2].kind, ChangeKind::Removed);
+
+        // hunks only keep three lines of context
+        let old: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        let mut new = old.clone();
+        new[2] = "two".to_string();
+        new[17] = "seventeen".to_string();
+        let diff = unified_diff("f", &old, &new);
+        assert!(diff.contains("@@ -1,6 +1,6 @@\n 0\n 1\n-2\n+two\n 3\n 4\n 5\n"));
+        assert!(diff.contains("@@ -15,6 +15,6 @@\n 14\n"));
+        assert!(!diff.contains(" 10\n"));
+        assert_eq!(diff_lines("a\nb"), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_minify_round_trip() {
+        fn tokens(code: &str) -> Vec<String> {
+            tokenize(code)
+                .unwrap()
+                .into_iter()
+                .filter(|token| token.kind != TokenKind::Comment)
+                .map(|token| code[token.span.start..token.span.end].to_string())
+                .collect()
+        }
+
+        let lua_file = fs::read_to_string("test_minify.lua").unwrap();
+        let minified = minify_lua(lua_file.clone());
+        assert_eq!(tokens(&lua_file), tokens(&minified));
+        assert_eq!(minify_lua(minified.clone()), minified);
+        assert!(!minified.contains("keep the old one around"));
+        assert!(!minified.contains("discards_left <= 0 then return"));
+        assert!(minified.contains(r#"sendDebugMessage('it\'s a "wild" card') end"#));
+        assert!(minified.contains("[[\nLine one\n    -- not a comment\nLine two]]"));
+        assert!(minified.contains(r#"local ratio = 1 - -G.GAME.probabilities.normal / 4"#));
+        assert!(minified.contains(r#"print(label..ratio) end"#));
+    }
+
     #[test]
     fn test_get_last_cli_version() {
         println!("Latest CLI version: {}", get_latest_cli_version());
@@ -40,4 +815,539 @@ mod tests {
     // {"id": "baz", "load_before": ["qux"], "load_after": []}
     // {"id": "qux", "load_before": [], "load_after": []}
     // Expected order: bar, baz, qux, test, foo
+
+    #[test]
+    fn test_overlay() {
+        assert_eq!(
+            normalize_path("./resources\\textures//1x/Jokers.png"),
+            "resources/textures/1x/Jokers.png"
+        );
+        assert_eq!(normalize_path("/main.lua"), "main.lua");
+
+        let mut overlay = Overlay::default();
+        overlay.register(
+            "resources/textures/1x/Jokers.png",
+            "foo",
+            "mods/foo/overlay/resources/textures/1x/Jokers.png",
+        );
+        overlay.register(
+            "./resources/textures/1x/Jokers.png",
+            "bar",
+            "mods/bar/jokers.png",
+        );
+        overlay.register("engine/text.lua", "foo", "mods/foo/overlay/engine/text.lua");
+        assert_eq!(
+            overlay.resolve("resources/textures/1x/Jokers.png"),
+            Some("mods/bar/jokers.png")
+        );
+        assert_eq!(
+            overlay.resolve("engine\\text.lua"),
+            Some("mods/foo/overlay/engine/text.lua")
+        );
+        assert_eq!(overlay.resolve("main.lua"), None);
+
+        // registering again moves the mod to the top
+        overlay.register(
+            "resources/textures/1x/Jokers.png",
+            "foo",
+            "mods/foo/jokers.png",
+        );
+        let entries = overlay.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].path, "resources/textures/1x/Jokers.png");
+        assert_eq!(entries[1].mod_id, "foo");
+        assert_eq!(entries[1].file, "mods/foo/jokers.png");
+        assert_eq!(entries[1].overridden, vec!["bar"]);
+        assert!(entries[0].overridden.is_empty());
+
+        let dir = std::env::temp_dir().join("balalib_test_overlay");
+        fs::create_dir_all(dir.join("resources/sounds")).unwrap();
+        fs::write(dir.join("resources/sounds/chips1.ogg"), "").unwrap();
+        fs::write(dir.join("main.lua"), "").unwrap();
+        assert_eq!(
+            scan_overlay_dir(&dir),
+            vec!["main.lua", "resources/sounds/chips1.ogg"]
+        );
+        assert!(scan_overlay_dir(&dir.join("missing")).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_localization_merge() {
+        let mut merge = LocaleMerge::new("fr");
+        merge.claim("descriptions.Joker.j_foo.name", "foo", || false);
+        merge.claim("descriptions.Joker.j_foo.name", "foo", || false);
+        merge.claim("misc.dictionary.k_hello", "foo", || false);
+        merge.claim("misc.dictionary.k_hello", "bar", || true);
+        assert!(merge.conflicts.is_empty());
+        merge.claim("descriptions.Joker.j_foo.name", "bar", || false);
+        assert_eq!(merge.conflicts.len(), 1);
+        assert_eq!(merge.conflicts[0].lang, "fr");
+        assert_eq!(merge.conflicts[0].key, "descriptions.Joker.j_foo.name");
+        assert_eq!(merge.conflicts[0].mod_id, "bar");
+        assert_eq!(merge.conflicts[0].other_mod_id, "foo");
+
+        let dir = std::env::temp_dir().join("balalib_test_localization");
+        fs::create_dir_all(dir.join("localization")).unwrap();
+        fs::write(dir.join("localization/fr.json"), "{}").unwrap();
+        fs::write(dir.join("localization/en-us.json"), "{}").unwrap();
+        fs::write(dir.join("localization/en-us.lua"), "return {}").unwrap();
+        assert_eq!(
+            locale_file(&dir, "fr"),
+            Some(dir.join("localization/fr.json"))
+        );
+        assert_eq!(
+            locale_file(&dir, "en-us"),
+            Some(dir.join("localization/en-us.lua"))
+        );
+        assert_eq!(locale_file(&dir, "de"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_command_usage() {
+        let args = parse_usage("give <card> [count:integer] [edition...]").unwrap();
+        assert_eq!(args.len(), 3);
+        assert_eq!(args[0].describe(), "<card>");
+        assert_eq!(args[1].describe(), "[count:integer]");
+        assert!(args[2].optional && args[2].rest);
+        assert!(parse_usage("money").unwrap().is_empty());
+        assert!(parse_usage("give [count] <card>").is_err());
+        assert!(parse_usage("give <words...> <card>").is_err());
+        assert!(parse_usage("give <count:float>").is_err());
+        assert!(parse_usage("give <card").is_err());
+
+        assert_eq!(
+            split_command_line(r#"give "Gros Michel" 2 it\'s"#).unwrap(),
+            vec!["give", "Gros Michel", "2", "it's"]
+        );
+        assert_eq!(split_command_line("  ").unwrap(), Vec::<String>::new());
+        assert!(split_command_line("give 'j_joker").is_err());
+
+        let words: Vec<String> = vec!["j_joker".to_string()];
+        assert_eq!(
+            parse_args(&args, &words).unwrap(),
+            vec![json!("j_joker"), json!(null), json!(null)]
+        );
+        let words: Vec<String> = ["j_joker", "2", "foil", "negative"]
+            .iter()
+            .map(|word| word.to_string())
+            .collect();
+        assert_eq!(
+            parse_args(&args, &words).unwrap(),
+            vec![json!("j_joker"), json!(2), json!("foil negative")]
+        );
+        let error = parse_args(&args, &["j_joker".to_string(), "two".to_string()]).unwrap_err();
+        assert_eq!(error, "Invalid integer for argument count: two");
+        assert!(parse_args(&args, &[]).is_err());
+        let flags = parse_usage("debug <enabled:bool>").unwrap();
+        assert_eq!(
+            parse_args(&flags, &["off".to_string()]).unwrap(),
+            vec![json!(false)]
+        );
+        assert!(parse_args(&flags, &["off".to_string(), "on".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_command_registry() {
+        let command = |name: &str, usage: &str| ModCommand {
+            name: name.to_string(),
+            lua_path: format!("mod.commands.{}", name),
+            short_description: String::new(),
+            usage: usage.to_string(),
+        };
+        let mut registry = CommandRegistry::default();
+        assert_eq!(
+            registry.add("foo", &command("give", "give <card>")),
+            Ok(None)
+        );
+        assert_eq!(
+            registry.add("foo", &command("debug", "debug <on:bool>")),
+            Ok(None)
+        );
+        let conflict = registry.add("bar", &command("give", "give <card> [count:integer]"));
+        let conflict = conflict.unwrap().unwrap();
+        assert_eq!(
+            (conflict.mod_id.as_str(), conflict.other_mod_id.as_str()),
+            ("foo", "bar")
+        );
+        assert!(registry.add("bar", &command("give", "give")).is_err());
+        assert!(registry
+            .add("bar", &command("spawn", "spawn [a] <b>"))
+            .is_err());
+
+        assert_eq!(registry.get("give").unwrap().mod_id, "foo");
+        assert_eq!(registry.get("bar:give").unwrap().mod_id, "bar");
+        assert!(registry.get("baz:give").is_none());
+
+        let completion = registry.complete("gi");
+        assert_eq!(completion.candidates, vec!["give"]);
+        let completion = registry.complete("");
+        assert_eq!(
+            completion.candidates,
+            vec!["bar:give", "debug", "foo:give", "give"]
+        );
+        let completion = registry.complete("bar:give j_joker ");
+        assert!(completion.candidates.is_empty());
+        assert_eq!(completion.hint.as_deref(), Some("[count:integer]"));
+        let completion = registry.complete("debug f");
+        assert_eq!(completion.candidates, vec!["debug false"]);
+        assert_eq!(completion.hint.as_deref(), Some("<on:boolean>"));
+        // the typed word is replaced from where it starts, quotes included
+        let completion = registry.complete("debug 't'");
+        assert_eq!(completion.candidates, vec!["debug true"]);
+        let completion = registry.complete("debug \"t");
+        assert!(completion.candidates.is_empty());
+        assert_eq!(
+            command_line_words("give «Gros Michel» \"é\"").unwrap(),
+            vec![
+                (0, "give".to_string()),
+                (5, "«Gros".to_string()),
+                (12, "Michel»".to_string()),
+                (21, "é".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_conversion_rules() {
+        let index = |i: i64| JsonKey::Index(i);
+        let name = |n: &str| JsonKey::Name(n.to_string());
+        assert_eq!(array_length(&[index(1), index(2), index(3)]), Some(3));
+        assert_eq!(array_length(&[index(3), index(1)]), Some(3));
+        assert_eq!(array_length(&[index(1), index(5)]), None);
+        assert_eq!(array_length(&[index(1), name("x")]), None);
+        assert_eq!(array_length(&[index(0), index(1)]), None);
+        assert_eq!(array_length(&[]), None);
+
+        assert_eq!(JsonKey::parse("12"), index(12));
+        assert_eq!(JsonKey::parse("-1"), index(-1));
+        assert_eq!(JsonKey::parse("012"), name("012"));
+        assert_eq!(JsonKey::parse("1.5"), name("1.5"));
+        assert_eq!(path_segment(&index(2)), "[2]");
+        assert_eq!(path_segment(&name("jokers")), ".jokers");
+        assert_eq!(path_segment(&name("a key")), r#"["a key"]"#);
+        assert_eq!(path_segment(&name("1x")), r#"["1x"]"#);
+
+        assert_eq!(special_number(f64::NAN), Some("NaN"));
+        assert_eq!(special_number(f64::NEG_INFINITY), Some("-Infinity"));
+        assert_eq!(special_number(1.5), None);
+        let bytes = [0u8, 0x9f, 0xff, b'a'];
+        assert_eq!(encode_bytes(&bytes), "009fff61");
+        assert_eq!(decode_bytes("009fff61"), Some(bytes.to_vec()));
+        assert_eq!(decode_bytes("9ff"), None);
+        assert_eq!(decode_bytes("zz"), None);
+    }
+
+    #[test]
+    fn test_json_output_options() {
+        let value = json!({"zeta": 1, "alpha": {"b": [1, 2], "a": "café"}});
+        let default = SerializeOptions::default();
+        assert_eq!(
+            write_json(&value, &default).unwrap(),
+            r#"{"zeta":1,"alpha":{"b":[1,2],"a":"café"}}"#
+        );
+        let sorted = SerializeOptions {
+            sort_keys: true,
+            ascii: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            write_json(&value, &sorted).unwrap(),
+            r#"{"alpha":{"a":"caf\u00e9","b":[1,2]},"zeta":1}"#
+        );
+        assert_eq!(
+            write_json(&value, &SerializeOptions::config()).unwrap(),
+            "{\n  \"alpha\": {\n    \"a\": \"café\",\n    \"b\": [\n      1,\n      2\n    ]\n  },\n  \"zeta\": 1\n}"
+        );
+        assert_eq!(escape_non_ascii("\"🃏\""), r#""\ud83c\udccf""#);
+
+        assert_eq!(round_significant(0.1 + 0.2, 14), 0.3);
+        assert_eq!(round_significant(1234.5678, 2), 1200.0);
+        assert_eq!(round_significant(-0.000123456, 3), -0.000123);
+        assert!(round_significant(f64::NAN, 3).is_nan());
+    }
+
+    #[test]
+    fn test_serialization_formats() {
+        let options = SerializeOptions::default();
+        let value = json!({"name": "Joker", "mult": 4, "rate": 0.5, "tags": ["common", "base"], "seen": {"1": true, "5": false}});
+
+        let toml = write_toml(&value, &options).unwrap();
+        assert_eq!(parse_toml(&toml).unwrap(), sorted_keys(value.clone()));
+        let yaml = write_yaml(&value, &options).unwrap();
+        assert!(yaml.contains("1: true"));
+        assert_eq!(parse_yaml(&yaml).unwrap(), value);
+        let msgpack = write_msgpack(&value, &options).unwrap();
+        assert_eq!(parse_msgpack(&msgpack).unwrap(), value);
+
+        // tagged values use the native types of each format
+        let special = json!({"nan": {"$number": "NaN"}, "bytes": {"$bytes": "ff00"}});
+        assert_eq!(
+            parse_msgpack(&write_msgpack(&special, &options).unwrap()).unwrap(),
+            special
+        );
+        assert_eq!(
+            parse_yaml(&write_yaml(&special, &options).unwrap()).unwrap(),
+            special
+        );
+        assert_eq!(
+            json_value_to_toml(&json!({"$number": "-Infinity"})).unwrap(),
+            toml::Value::Float(f64::NEG_INFINITY)
+        );
+        assert_eq!(
+            parse_toml("when = 1979-05-27\nratio = inf").unwrap(),
+            json!({"when": "1979-05-27", "ratio": {"$number": "Infinity"}})
+        );
+
+        // errors carry the path of the value
+        let error = json_value_to_toml(&json!({"jokers": [1, null]})).unwrap_err();
+        assert_eq!(error.to_string(), "TOML has no null at $.jokers[2]");
+        assert!(write_toml(&json!([1, 2]), &options).is_err());
+        assert_eq!(
+            parse_yaml("a:\n  [1, 2]: x").unwrap_err(),
+            "Unsupported YAML key at $.a"
+        );
+        assert!(parse_msgpack(&[0x92, 0x01]).is_err());
+        assert_eq!(
+            parse_msgpack(&[0x91, 0xd4, 0x01, 0x00]).unwrap_err(),
+            "Unsupported MessagePack extension at $[1]"
+        );
+    }
+
+    // Tests that need a Lua state, see the features in Cargo.toml
+    #[cfg(not(feature = "module"))]
+    mod lua_state {
+        use crate::core::inject;
+        use crate::localization::{merge_locale, LocaleMerge};
+        use crate::lua::patch::{TextMode, TextTarget};
+        use crate::persistence::write_save_file;
+        use crate::serialization::{json_to_lua, lua_to_json, lua_value_to_json_value};
+        use crate::structs::serializeoptions::SerializeOptions;
+        use crate::utils::minify_lua;
+        use mlua::{Lua, Value};
+        use std::fs;
+
+        // A game file with a local, and its function in game_state
+        fn counter_game(lua: &Lua) {
+            lua.load(
+                "Counter = {} \
+                 local count = 0 \
+                 function Counter.bump() count = count + 1 return count end \
+                 function Counter.get() return count end",
+            )
+            .exec()
+            .unwrap();
+            let code = "function Counter.bump() count = count + 1 return count end";
+            let file = lua.create_table().unwrap();
+            file.set("Counter.bump", minify_lua(code.to_string()))
+                .unwrap();
+            let game_state = lua.create_table().unwrap();
+            game_state.set("counter", file).unwrap();
+            lua.globals().set("game_state", game_state).unwrap();
+        }
+
+        fn patch(lua: &Lua, find: &str, insert: &str, mode: TextMode) -> mlua::Result<usize> {
+            let target = TextTarget {
+                mode,
+                ..Default::default()
+            };
+            let (file, function) = ("counter".to_string(), "Counter.bump".to_string());
+            inject(
+                lua,
+                file,
+                function,
+                find.into(),
+                insert.into(),
+                target,
+                None,
+            )
+        }
+
+        #[test]
+        fn test_failed_patch_keeps_function() {
+            let lua = Lua::new();
+            counter_game(&lua);
+            let bump: mlua::Function = lua.load("return Counter.bump").eval().unwrap();
+            let stored = || -> String {
+                lua.load("return game_state.counter['Counter.bump']")
+                    .eval()
+                    .unwrap()
+            };
+            let before = stored();
+
+            let error = patch(&lua, "count + 1", "count +", TextMode::Replace).unwrap_err();
+            assert!(error.to_string().contains("Patch of unknown breaks"));
+            // defines the function, then fails
+            let after = TextMode::After;
+            let error = patch(&lua, "return count end", " error('boom')", after).unwrap_err();
+            assert!(error.to_string().contains("boom"));
+            assert_eq!(stored(), before);
+            let current: mlua::Function = lua.load("return Counter.bump").eval().unwrap();
+            assert_eq!(current, bump);
+        }
+
+        #[test]
+        fn test_patch_keeps_game_upvalues() {
+            // upvalues are read through the debug library
+            let lua = unsafe { Lua::unsafe_new() };
+            counter_game(&lua);
+            patch(&lua, "count + 1", "count + 2", TextMode::Replace).unwrap();
+            // a mod wraps the function, with a local of the same name
+            lua.load(
+                "local count = 100 local bump = Counter.bump \
+                 Counter.bump = function() local _ = count return bump() end",
+            )
+            .exec()
+            .unwrap();
+            patch(&lua, "count + 2", "count + 3", TextMode::Replace).unwrap();
+            let count: i64 = lua.load("return Counter.bump()").eval().unwrap();
+            assert_eq!(count, 3);
+        }
+
+        #[test]
+        fn test_write_save_file() {
+            let dir = std::env::temp_dir().join("balalib_test_save_file");
+            let lua = Lua::new();
+            lua.globals()
+                .set("save_dir", dir.to_str().unwrap())
+                .unwrap();
+            lua.load("love = {filesystem = {getSaveDirectory = function() return save_dir end}}")
+                .exec()
+                .unwrap();
+            let write = |name: &str, contents: &[u8]| {
+                let contents = lua.create_string(contents).unwrap();
+                write_save_file(&lua, name.to_string(), contents)
+            };
+            write("1/profile.jkr", b"\x00first").unwrap();
+            write("1/profile.jkr", b"\x00second").unwrap();
+            assert_eq!(fs::read(dir.join("1/profile.jkr")).unwrap(), b"\x00second");
+            assert_eq!(
+                fs::read(dir.join("1/profile.jkr.bak")).unwrap(),
+                b"\x00first"
+            );
+            assert!(write("../profile.jkr", b"").is_err());
+            assert!(write("/tmp/profile.jkr", b"").is_err());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn test_table_cycles() {
+            let lua = Lua::new();
+            let value: Value = lua
+                .load("local t = {name = 'root', list = {1, 2}} t.list[3] = t return t")
+                .eval()
+                .unwrap();
+            let error = lua_value_to_json_value(&value).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "Table contains itself, it can only be converted with refs at $.list[3]"
+            );
+        }
+
+        #[test]
+        fn test_table_refs_round_trip() {
+            let lua = Lua::new();
+            let value: Value = lua
+                .load(
+                    "local shared = {n = 1} \
+                     local t = {b = shared, a = shared, list = {shared}, items = {4, 5}} \
+                     t.self = t t.more = t.items return t",
+                )
+                .eval()
+                .unwrap();
+            // sorted keys write some of the refs before the tables they point to
+            let options = SerializeOptions {
+                refs: true,
+                sort_keys: true,
+                ..Default::default()
+            };
+            let json = lua_to_json(value, options).unwrap();
+            let back = json_to_lua(&lua, json).unwrap();
+            lua.globals().set("back", back).unwrap();
+            let same: bool = lua
+                .load(
+                    "return back.self == back and back.a == back.b and back.list[1] == back.a \
+                     and back.a.n == 1 and back.more == back.items and back.items[2] == 5",
+                )
+                .eval()
+                .unwrap();
+            assert!(same);
+
+            let back = json_to_lua(
+                &lua,
+                r#"{"a": {"$ref": 1}, "b": {"$id": 1, "n": 2}}"#.into(),
+            );
+            lua.globals().set("back", back.unwrap()).unwrap();
+            assert!(lua.load("return back.a == back.b").eval::<bool>().unwrap());
+            let error = json_to_lua(&lua, r#"{"a": {"$ref": 7}}"#.into()).unwrap_err();
+            assert!(error.to_string().contains("$ref 7 has no table"));
+            let error = json_to_lua(&lua, r#"[{"$id": 1}, {"$id": 1}]"#.into()).unwrap_err();
+            assert!(error.to_string().contains("Duplicate $id 1"));
+        }
+
+        #[test]
+        fn test_table_tag_keys_round_trip() {
+            let lua = Lua::new();
+            let value: Value = lua
+                .load(
+                    "local shared = {}                      return {a = {['$number'] = 'NaN'}, b = {['$id'] = 1, ['$$ref'] = 2},                      c = {['$ref'] = 1}, d = {['$schema'] = 'x'}, e = shared, f = shared}",
+                )
+                .eval()
+                .unwrap();
+            let options = SerializeOptions {
+                refs: true,
+                sort_keys: true,
+                ..Default::default()
+            };
+            let json = lua_to_json(value.clone(), options).unwrap();
+            assert_eq!(
+                json,
+                r#"{"a":{"$$number":"NaN"},"b":{"$$$ref":2,"$$id":1},"c":{"$$ref":1},"d":{"$schema":"x"},"e":{"$id":1},"f":{"$ref":1}}"#
+            );
+            let back = json_to_lua(&lua, json).unwrap();
+            assert_eq!(
+                lua_value_to_json_value(&back).unwrap(),
+                lua_value_to_json_value(&value).unwrap()
+            );
+        }
+
+        #[test]
+        fn test_table_max_depth() {
+            let lua = Lua::new();
+            let value: Value = lua
+                .load("local s = {} return {x = {y = s, z = {1}}, w = {v = s}}")
+                .eval()
+                .unwrap();
+            let options = SerializeOptions {
+                max_depth: Some(1),
+                refs: true,
+                sort_keys: true,
+                ..Default::default()
+            };
+            assert_eq!(
+                lua_to_json(value, options).unwrap(),
+                r#"{"w":{"v":{"$truncated":true}},"x":{"y":{"$truncated":true},"z":{"$truncated":true}}}"#
+            );
+        }
+
+        #[test]
+        fn test_locale_section_keeps_text() {
+            let lua = Lua::new();
+            let target = lua
+                .load("return { misc = { dictionary = 'Dictionary' } }")
+                .eval()
+                .unwrap();
+            let source = lua
+                .load("return { misc = { dictionary = { k_new = 'New' }, v_new = 'New' } }")
+                .eval()
+                .unwrap();
+            let mut merge = LocaleMerge::new("en-us");
+            merge_locale(&lua, &target, &source, "", "test", false, &mut merge).unwrap();
+            let misc: mlua::Table = target.get("misc").unwrap();
+            assert_eq!(misc.get::<_, String>("dictionary").unwrap(), "Dictionary");
+            assert_eq!(misc.get::<_, String>("v_new").unwrap(), "New");
+            assert!(merge.conflicts.is_empty());
+        }
+    }
 }
@@ -0,0 +1,160 @@
+use crate::core::get_love_dir;
+use crate::structs::modevent::{ModEvent, ModEventKind};
+use mlua::prelude::LuaResult;
+use mlua::{Lua, UserData, UserDataMethods};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_INTERVAL_MS: u64 = 500;
+
+// mod id -> (path relative to the mod folder -> (modified time, size))
+pub type Snapshot = BTreeMap<String, BTreeMap<String, (Option<SystemTime>, u64)>>;
+
+pub struct ModWatcher {
+    events: Arc<Mutex<VecDeque<ModEvent>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl UserData for ModWatcher {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("poll", |_, watcher, ()| Ok(watcher.poll()));
+        methods.add_method("stop", |_, watcher, ()| {
+            watcher.stop();
+            Ok(())
+        });
+        methods.add_method("is_running", |_, watcher, ()| {
+            Ok(watcher.running.load(Ordering::SeqCst))
+        });
+    }
+}
+
+impl ModWatcher {
+    pub fn start(mods_dir: String, interval: Duration) -> ModWatcher {
+        let events = Arc::new(Mutex::new(VecDeque::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_events = events.clone();
+        let thread_running = running.clone();
+        std::thread::spawn(move || {
+            let mut snapshot = scan_mods_dir(Path::new(&mods_dir));
+            while thread_running.load(Ordering::SeqCst) {
+                std::thread::sleep(interval);
+                let next = scan_mods_dir(Path::new(&mods_dir));
+                let changes = diff_snapshots(&snapshot, &next);
+                if !changes.is_empty() {
+                    thread_events.lock().unwrap().extend(changes);
+                }
+                snapshot = next;
+            }
+        });
+        ModWatcher { events, running }
+    }
+
+    // Drains every event queued since the last call, meant to be called once per frame
+    pub fn poll(&self) -> Vec<ModEvent> {
+        self.events.lock().unwrap().drain(..).collect()
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Drop for ModWatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+pub fn watch_mods(lua: &Lua, interval_ms: Option<u64>) -> LuaResult<ModWatcher> {
+    let love_dir = get_love_dir(lua)?;
+    let mods_dir = format!("{}/mods", love_dir);
+    let interval = Duration::from_millis(interval_ms.unwrap_or(DEFAULT_INTERVAL_MS));
+    Ok(ModWatcher::start(mods_dir, interval))
+}
+
+pub fn scan_mods_dir(mods_dir: &Path) -> Snapshot {
+    fn scan_files(
+        dir: &Path,
+        prefix: &str,
+        files: &mut BTreeMap<String, (Option<SystemTime>, u64)>,
+    ) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+            // symlinks aren't followed, a link back to a parent folder would never end
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                scan_files(&path, &format!("{}{}/", prefix, name), files);
+            } else if !name.ends_with(".tmp") && !name.ends_with(".bak") {
+                // temp and backup files are churned by our own atomic writes
+                let metadata = entry.metadata().ok();
+                files.insert(
+                    format!("{}{}", prefix, name),
+                    (
+                        metadata.as_ref().and_then(|m| m.modified().ok()),
+                        metadata.map(|m| m.len()).unwrap_or(0),
+                    ),
+                );
+            }
+        }
+    }
+
+    let mut snapshot = Snapshot::new();
+    let entries = match std::fs::read_dir(mods_dir) {
+        Ok(entries) => entries,
+        Err(_) => return snapshot,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let mut files = BTreeMap::new();
+        scan_files(&path, "", &mut files);
+        snapshot.insert(entry.file_name().to_string_lossy().to_string(), files);
+    }
+    snapshot
+}
+
+pub fn diff_snapshots(old: &Snapshot, new: &Snapshot) -> Vec<ModEvent> {
+    let mut events = Vec::new();
+    let mod_ids: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    for mod_id in mod_ids {
+        let (old_files, new_files) = match (old.get(mod_id), new.get(mod_id)) {
+            (Some(old_files), Some(new_files)) => (old_files, new_files),
+            (old_files, _) => {
+                events.push(ModEvent {
+                    kind: if old_files.is_some() {
+                        ModEventKind::Removed
+                    } else {
+                        ModEventKind::Added
+                    },
+                    mod_id: mod_id.clone(),
+                    path: None,
+                });
+                continue;
+            }
+        };
+        let paths: BTreeSet<&String> = old_files.keys().chain(new_files.keys()).collect();
+        for path in paths {
+            let kind = match (old_files.get(path), new_files.get(path)) {
+                (None, Some(_)) => ModEventKind::Added,
+                (Some(_), None) => ModEventKind::Removed,
+                (Some(old_file), Some(new_file)) if old_file != new_file => ModEventKind::Changed,
+                _ => continue,
+            };
+            events.push(ModEvent {
+                kind,
+                mod_id: mod_id.clone(),
+                path: Some(path.clone()),
+            });
+        }
+    }
+    events
+}
@@ -0,0 +1,39 @@
+--[==[
+    Balatro-style snippets that used to trip up the minifier.
+    A ]] inside this comment does not end it.
+]==]
+function Card:set_ability(center, initial, delay_sprites)
+    local old_center = self.config.center -- keep the old one around
+    self.ability = {
+        name = center.name,
+        effect = center.effect,
+        set = center.set,
+        mult = center.config.mult or 0,
+        x_mult = center.config.Xmult or 1,
+    }
+    if self.ability.name == "Joker's Wild" then
+        sendDebugMessage('it\'s a "wild" card')
+    end
+    self.ability.text = [[
+Line one
+    -- not a comment
+Line two]]
+    self.ability.desc = [==[contains ]] and [[ brackets]==]
+    return old_center
+end
+
+G.FUNCS.can_discard = function(e)
+    --[[ if G.GAME.current_round.discards_left <= 0 then
+        return
+    end ]]
+    if G.GAME.current_round.discards_left <= 0 or #G.hand.highlighted <= 0 then
+        e.config.colour = G.C.UI.BACKGROUND_INACTIVE
+        e.config.button = nil
+    else
+        e.config.colour = G.C.RED
+        e.config.button = 'discard_cards_from_highlighted'
+    end
+    local ratio = 1 - -G.GAME.probabilities.normal / 4
+    local label = 'x' .. 1 .. "\\" .. "\n"
+    print(label..ratio)
+end